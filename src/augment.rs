@@ -0,0 +1,71 @@
+use image::GenericImageView;
+
+// xorshift64, so that the variants of an image are the same on every run and
+// cached hashes stay valid.
+struct Random(u64);
+
+impl Random {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn range(&mut self, min: f32, max: f32) -> f32 {
+        min + (max - min) * (self.next() >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+// Perturbed copies of a dataset image, to cover some of the noise in camera
+// warps that pristine scans don't have.
+pub fn variants(image: &image::DynamicImage, count: usize) -> Vec<image::DynamicImage> {
+    (0..count).map(|i| variant(image, i as u64)).collect()
+}
+
+fn variant(image: &image::DynamicImage, seed: u64) -> image::DynamicImage {
+    let mut random = Random((seed + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15));
+
+    let (width, height) = image.dimensions();
+    let (w, h) = (width as f32, height as f32);
+
+    // Perspective jitter: every corner moves by up to 2% of the card size.
+    let from = [(0.0, 0.0), (w, 0.0), (w, h), (0.0, h)];
+    let mut to = from;
+    for corner in to.iter_mut() {
+        corner.0 += random.range(-0.02, 0.02) * w;
+        corner.1 += random.range(-0.02, 0.02) * h;
+    }
+
+    let rgb = image.to_rgb8();
+    let warped = match imageproc::geometric_transformations::Projection::from_control_points(from, to) {
+        Some(projection) => imageproc::geometric_transformations::warp(
+            &rgb,
+            &projection,
+            imageproc::geometric_transformations::Interpolation::Bilinear,
+            image::Rgb([0, 0, 0]),
+        ),
+        None => rgb,
+    };
+
+    // Crop offset: up to 2% off each side.
+    let left = (random.range(0.0, 0.02) * w) as u32;
+    let right = (random.range(0.0, 0.02) * w) as u32;
+    let top = (random.range(0.0, 0.02) * h) as u32;
+    let bottom = (random.range(0.0, 0.02) * h) as u32;
+
+    let output = image::DynamicImage::ImageRgb8(warped)
+        .crop_imm(left, top, width - left - right, height - top - bottom)
+        .blur(random.range(0.3, 1.5))
+        .adjust_contrast(random.range(-15.0, 15.0))
+        .brighten(random.range(-20.0, 20.0) as i32);
+
+    // JPEG recompression.
+    let quality = random.range(40.0, 85.0) as u8;
+    let mut jpeg = vec![];
+    output
+        .write_to(&mut jpeg, image::ImageOutputFormat::Jpeg(quality))
+        .ok()
+        .and_then(|_| image::load_from_memory(&jpeg).ok())
+        .unwrap_or(output)
+}
@@ -0,0 +1,134 @@
+use detection::*;
+
+fn find(parents: &mut [usize], i: usize) -> usize {
+    let mut root = i;
+    while parents[root] != root {
+        root = parents[root];
+    }
+
+    let mut current = i;
+    while parents[current] != root {
+        let next = parents[current];
+        parents[current] = root;
+        current = next;
+    }
+
+    root
+}
+
+fn set_of(entry: &DatasetEntry) -> String {
+    entry.metadata.set.clone().unwrap_or_else(|| "?".to_string())
+}
+
+// ambiguity-report [threshold]
+//
+// Clusters dataset entries whose card hashes are within the threshold of each
+// other (10% of the maximum distance by default). Clusters spanning several
+// sets can only be told apart by the set symbol, clusters within a single set
+// only by the collector number.
+fn main() {
+    let config = load_hashing_config().unwrap_or_else(|e| panic!("failed to load {}: {}", HASHING_CONFIG, e));
+
+    let dataset = load_or_build_dataset(
+        "dataset/",
+        "dataset.bin",
+        &config,
+        &metadata::MetadataSource::default(),
+    );
+
+    let threshold = std::env::args()
+        .nth(1)
+        .map(|t| t.parse().expect("threshold must be a number"))
+        .unwrap_or(dataset.card.bits() / 10);
+
+    let mut parents = (0..dataset.len()).collect::<Vec<_>>();
+    let mut closest = vec![u32::MAX; dataset.len()];
+
+    for (entry, closest) in closest.iter_mut().enumerate() {
+        for row in dataset.rows(entry) {
+            for (other, distance) in dataset.within(dataset.card.row(row), threshold) {
+                if other == entry {
+                    continue;
+                }
+
+                *closest = (*closest).min(distance);
+
+                let (a, b) = (find(&mut parents, entry), find(&mut parents, other));
+                parents[a.max(b)] = a.min(b);
+            }
+        }
+    }
+
+    let mut clusters = std::collections::BTreeMap::new();
+    for entry in 0..dataset.len() {
+        let root = find(&mut parents, entry);
+        clusters.entry(root).or_insert_with(Vec::new).push(entry);
+    }
+
+    let templates = load_templates(&dataset)
+        .unwrap_or_else(|e| panic!("failed to load templates: {}", e))
+        .into_iter()
+        .map(|t| t.code)
+        .collect::<std::collections::HashSet<_>>();
+
+    let mut set_pairs = std::collections::BTreeMap::new();
+    let mut within_sets = std::collections::BTreeMap::new();
+
+    let clusters = clusters.into_values().filter(|c| c.len() > 1).collect::<Vec<_>>();
+    for (i, cluster) in clusters.iter().enumerate() {
+        let sets = cluster
+            .iter()
+            .map(|&e| set_of(&dataset.entries[e]))
+            .collect::<std::collections::BTreeSet<_>>();
+
+        println!("cluster {}: {} entries, sets {:?}", i, cluster.len(), sets);
+        for &entry in cluster.iter() {
+            println!("  {:?} ({}, closest {})", dataset.entries[entry].path, set_of(&dataset.entries[entry]), closest[entry]);
+        }
+
+        let sets = sets.into_iter().collect::<Vec<_>>();
+        for (j, a) in sets.iter().enumerate() {
+            for b in sets[j + 1..].iter() {
+                *set_pairs.entry((a.clone(), b.clone())).or_insert(0) += 1;
+            }
+        }
+
+        for set in sets.iter() {
+            if cluster.iter().filter(|&&e| set_of(&dataset.entries[e]) == *set).count() > 1 {
+                *within_sets.entry(set.clone()).or_insert(0) += 1;
+            }
+        }
+    }
+
+    println!();
+    println!("set pairs that need set symbol templates:");
+    for ((a, b), count) in set_pairs.iter() {
+        let missing = [a, b]
+            .iter()
+            .filter(|set| !templates.contains(set.as_str()))
+            .map(|set| set.as_str())
+            .collect::<Vec<_>>();
+
+        if missing.is_empty() {
+            println!("  {} / {}: {} clusters", a, b, count);
+        } else {
+            println!("  {} / {}: {} clusters, no template for {}", a, b, count, missing.join(", "));
+        }
+    }
+
+    println!();
+    println!("sets that need collector numbers:");
+    for (set, count) in within_sets.iter() {
+        println!("  {}: {} clusters", set, count);
+    }
+
+    println!();
+    println!(
+        "{} entries, {} clusters within distance {}, {} set pairs, {} sets",
+        dataset.len(),
+        clusters.len(),
+        threshold,
+        set_pairs.len(),
+        within_sets.len(),
+    );
+}
@@ -1,5 +1,88 @@
-use detection::load_or_build_dataset;
+use detection::catalog_sources;
+use detection::load_hashing_config;
+use detection::update_dataset;
+use detection::HASHING_CONFIG;
+use detection::metadata::MetadataSource;
+use detection::pokemontcg;
 
+const POKEMONTCG_DATA: &str = "pokemontcg-data/";
+
+// cache-dataset [--augment <variants>] [<name>=<directory>:<cache>:<priority> ...]
+//
+// Brings the cache of every given catalog (dataset/ and dataset.bin by
+// default) up to date.
 fn main() {
-    load_or_build_dataset("dataset/", "dataset.txt");
+    let mut augmented = 0;
+    let mut specs = vec![];
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--augment" {
+            augmented = args.next().and_then(|n| n.parse().ok()).expect("--augment takes a number of variants");
+        } else {
+            specs.push(arg);
+        }
+    }
+
+    let sources = catalog_sources(&specs).unwrap_or_else(|e| panic!("{}", e));
+
+    let mut metadata = MetadataSource::default();
+
+    if std::path::Path::new(POKEMONTCG_DATA).is_dir() {
+        let catalog = pokemontcg::load(std::path::Path::new(POKEMONTCG_DATA))
+            .expect("failed to read the pokemontcg.io data");
+
+        println!("{} cards in {}", catalog.len(), POKEMONTCG_DATA);
+        metadata.catalog = Some(catalog);
+    }
+
+    let config = load_hashing_config().unwrap_or_else(|e| panic!("failed to load {}: {}", HASHING_CONFIG, e));
+
+    for source in sources.iter() {
+        let (dataset, update) = update_dataset(
+            &source.dataset_path,
+            &source.cache_filename,
+            &config,
+            &metadata,
+            augmented,
+        );
+
+        for path in update.added.iter() {
+            println!("added {:?}", path);
+        }
+        for path in update.changed.iter() {
+            println!("changed {:?}", path);
+        }
+        for path in update.removed.iter() {
+            println!("removed {:?}", path);
+        }
+
+        if let Some(catalog) = &metadata.catalog {
+            let report = pokemontcg::join_report(catalog, &dataset);
+
+            for path in report.images_without_metadata.iter() {
+                println!("no metadata for {:?}", path);
+            }
+            for id in report.metadata_without_images.iter() {
+                println!("no image for {}", id);
+            }
+
+            println!(
+                "{}: {} images without metadata, {} cards without images",
+                source.name,
+                report.images_without_metadata.len(),
+                report.metadata_without_images.len(),
+            );
+        }
+
+        println!(
+            "{}: {} entries: {} added, {} changed, {} removed, {} unchanged",
+            source.name,
+            dataset.len(),
+            update.added.len(),
+            update.changed.len(),
+            update.removed.len(),
+            update.unchanged,
+        );
+    }
 }
@@ -0,0 +1,86 @@
+use detection::*;
+
+// calibrate-confidence [labels]
+//
+// Fits the confidence model on every candidate of every labeled photo (see
+// validation.rs). Reliability is reported on half of the photos for a model
+// fitted on the other half, and the model written to CONFIDENCE_FILE, where
+// the detectors read it from, is fitted on all of them.
+fn main() {
+    let labels_filename = std::env::args().nth(1).unwrap_or_else(|| "validation/labels.txt".to_string());
+    let labels = validation::read_labels(&labels_filename).expect("failed to read labels");
+
+    let config = load_hashing_config().unwrap_or_else(|e| panic!("failed to load {}: {}", HASHING_CONFIG, e));
+
+    let dataset = load_or_build_dataset(
+        "dataset/",
+        "dataset.bin",
+        &config,
+        &metadata::MetadataSource::default(),
+    );
+    let options = ProcessingOptions {
+        glare_masking: true,
+        ..Default::default()
+    };
+    let templates = load_templates(&dataset).unwrap_or_else(|e| panic!("failed to load templates: {}", e));
+
+    let width = 1920;
+    let height = 1080;
+
+    let mut buffers = ProcessingBuffers::new(width, height);
+    let mut frame = vec![];
+    let mut photos = vec![];
+
+    for label in labels.iter() {
+        let photo = image::open(&label.image).expect("failed to read image");
+        buffers.load_photo(&photo, width, height, &mut frame);
+
+        let mut processing = ProcessingPipeline {
+            frame: &frame,
+            buffers: &mut buffers,
+            options: &options,
+        };
+
+        let (_, outcome, _) = process(&mut processing, &dataset, &templates);
+        let samples = match &outcome {
+            Some(outcome) => outcome
+                .candidates()
+                .iter()
+                .map(|c| (c.evidence, label.is(c.entry)))
+                .collect::<Vec<_>>(),
+            None => vec![],
+        };
+
+        if samples.is_empty() {
+            println!("{:?}: no card found", label.image);
+            continue;
+        }
+
+        photos.push(samples);
+    }
+
+    let half = |parity: usize| {
+        photos
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| i % 2 == parity)
+            .flat_map(|(_, samples)| samples.iter().copied())
+            .collect::<Vec<_>>()
+    };
+
+    let held_out = half(1);
+    let model = confidence::ConfidenceModel::fit(&half(0));
+
+    println!("confidence  samples  mean  right");
+    for (lowest, count, mean, right) in confidence::reliability(&model, &held_out, 10) {
+        println!("{:>10.1}  {:>7}  {:.2}  {:.2}", lowest, count, mean, right);
+    }
+
+    let all = photos.into_iter().flatten().collect::<Vec<_>>();
+    let model = confidence::ConfidenceModel::fit(&all);
+    println!();
+    println!("{:?}", model);
+
+    save_confidence(&config, &model).unwrap();
+    println!("written to {}", CONFIDENCE_FILE);
+}
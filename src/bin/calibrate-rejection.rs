@@ -0,0 +1,94 @@
+use detection::*;
+
+// calibrate-rejection [labels]
+//
+// Runs every labeled photo (see validation.rs) through process without
+// rejecting anything, and writes the rejection thresholds that get the most
+// of them right to REJECTION_FILE, where the detectors read them from.
+fn main() {
+    let labels_filename = std::env::args().nth(1).unwrap_or_else(|| "validation/labels.txt".to_string());
+    let labels = validation::read_labels(&labels_filename).expect("failed to read labels");
+
+    let config = load_hashing_config().unwrap_or_else(|e| panic!("failed to load {}: {}", HASHING_CONFIG, e));
+
+    let dataset = load_or_build_dataset(
+        "dataset/",
+        "dataset.bin",
+        &config,
+        &metadata::MetadataSource::default(),
+    );
+    let options = ProcessingOptions {
+        glare_masking: true,
+        ..Default::default()
+    };
+    let templates = load_templates(&dataset).unwrap_or_else(|e| panic!("failed to load templates: {}", e));
+
+    let width = 1920;
+    let height = 1080;
+
+    let mut buffers = ProcessingBuffers::new(width, height);
+    let mut frame = vec![];
+    let mut samples = vec![];
+
+    for label in labels.iter() {
+        let photo = image::open(&label.image).expect("failed to read image");
+        buffers.load_photo(&photo, width, height, &mut frame);
+
+        let mut processing = ProcessingPipeline {
+            frame: &frame,
+            buffers: &mut buffers,
+            options: &options,
+        };
+
+        let (_, outcome, _) = process(&mut processing, &dataset, &templates);
+        let candidates = match &outcome {
+            Some(outcome) if !outcome.candidates().is_empty() => outcome.candidates(),
+            _ => {
+                println!("{:?}: no card found", label.image);
+                continue;
+            },
+        };
+
+        // What process applies rejection to: the nearest candidate, and its
+        // gap to the second nearest. Right means the top ranked one is right.
+        let best = &candidates[0];
+        let mut distances = candidates.iter().map(|c| c.distance).collect::<Vec<_>>();
+        distances.sort_unstable();
+        let sample = rejection::Sample {
+            distance: distances[0],
+            gap: distances.get(1).map(|second| second.saturating_sub(distances[0])),
+            known: label.card.is_some(),
+            correct: label.is(best.entry),
+        };
+
+        println!(
+            "{:?}: {:?} ({}, gap {:?}) {}",
+            label.image,
+            best.entry.path,
+            sample.distance,
+            sample.gap,
+            if sample.correct { "right" } else if sample.known { "wrong" } else { "unknown" },
+        );
+        samples.push(sample);
+    }
+
+    let (rejection, right) = rejection::calibrate(&samples);
+    let known = samples.iter().filter(|s| s.known).count();
+    let accepted_right = samples.iter().filter(|s| s.correct && rejection.accepts(s.distance, s.gap)).count();
+    let rejected_unknown = samples.iter().filter(|s| !s.known && !rejection.accepts(s.distance, s.gap)).count();
+
+    println!();
+    println!("{:?}", rejection);
+    println!(
+        "{}/{} right: {}/{} known cards matched, {}/{} unknown cards rejected",
+        right,
+        samples.len(),
+        accepted_right,
+        known,
+        rejected_unknown,
+        samples.len() - known,
+    );
+
+    save_rejection(&config, &rejection).unwrap();
+    println!("written to {}", REJECTION_FILE);
+}
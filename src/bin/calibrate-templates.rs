@@ -0,0 +1,145 @@
+use detection::*;
+use image::GenericImageView;
+use rayon::prelude::*;
+
+// calibrate-templates [labels] [--rate <false positive rate>] [--search <exact|narrow|wide>]
+//
+// Scores every set symbol template on every labeled image (see
+// validation.rs), already warped if it's 734x1024 or warped by process
+// otherwise, over the given search range (SearchRange::default() by default),
+// and writes the thresholds that let at most the given fraction (1% by
+// default) of other sets' cards through back to the template manifest, along
+// with the range, which the detectors then search.
+fn main() {
+    let mut labels_filename = "validation/labels.txt".to_string();
+    let mut rate = 0.01;
+    let mut search = set_symbol_detection::SearchRange::default();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--rate" {
+            rate = args.next().and_then(|r| r.parse().ok()).expect("--rate takes a number");
+        } else if arg == "--search" {
+            search = args
+                .next()
+                .and_then(|s| set_symbol_detection::SearchRange::named(&s))
+                .expect("--search takes exact, narrow or wide");
+        } else {
+            labels_filename = arg;
+        }
+    }
+
+    let labels = validation::read_labels(&labels_filename).expect("failed to read labels");
+
+    let config = load_hashing_config().unwrap_or_else(|e| panic!("failed to load {}: {}", HASHING_CONFIG, e));
+
+    let dataset = load_or_build_dataset(
+        "dataset/",
+        "dataset.bin",
+        &config,
+        &metadata::MetadataSource::default(),
+    );
+    let options = ProcessingOptions {
+        glare_masking: true,
+        ..Default::default()
+    };
+    let mut templates = load_templates(&dataset).unwrap_or_else(|e| panic!("failed to load templates: {}", e));
+    if templates.is_empty() {
+        println!("no templates to calibrate");
+        return;
+    }
+
+    let width = 1920;
+    let height = 1080;
+
+    let mut buffers = ProcessingBuffers::new(width, height);
+    let mut frame = vec![];
+
+    // Per template, scores on cards of its set and on cards of other sets.
+    let mut positives = vec![vec![]; templates.len()];
+    let mut negatives = vec![vec![]; templates.len()];
+
+    for label in labels.iter() {
+        let card = label.card.as_ref().map(|card| {
+            dataset
+                .entries
+                .iter()
+                .find(|e| label.is(e))
+                .unwrap_or_else(|| panic!("{:?}: {} isn't in the dataset", label.image, card))
+        });
+
+        let photo = image::open(&label.image).expect("failed to read image");
+        let warped = if photo.dimensions() == (734, 1024) {
+            photo.to_luma8()
+        } else {
+            buffers.load_photo(&photo, width, height, &mut frame);
+
+            let mut processing = ProcessingPipeline {
+                frame: &frame,
+                buffers: &mut buffers,
+                options: &options,
+            };
+
+            if process(&mut processing, &dataset, &[]).1.is_none() {
+                println!("{:?}: no card found", label.image);
+                continue;
+            }
+
+            buffers.perspective_image.to_luma8()
+        };
+
+        // The layouts process would search in if the card was shortlisted.
+        let layouts = match card.and_then(|c| layout::Era::of(&c.metadata)) {
+            Some(era) => vec![layout::Layout::of(era)],
+            None => layout::profiles(),
+        };
+        let layouts = layouts.iter().collect::<Vec<_>>();
+
+        let scores = templates
+            .par_iter()
+            .map(|t| {
+                let areas = t.search_areas(&layouts, 734, 1024);
+                set_symbol_detection::detect(&warped, &t.image, t.threshold, &areas, &search, false).score
+            })
+            .collect::<Vec<_>>();
+
+        for (i, (template, score)) in templates.iter().zip(scores).enumerate() {
+            // Nowhere to search.
+            if !score.is_finite() {
+                continue;
+            }
+
+            if card.map(|c| c.metadata.in_set(&template.code)).unwrap_or(false) {
+                positives[i].push(score);
+            } else {
+                negatives[i].push(score);
+            }
+        }
+
+        println!("{:?}: {}", label.image, card.and_then(|c| c.metadata.set.as_deref()).unwrap_or("unknown"));
+    }
+
+    println!();
+    for (i, template) in templates.iter_mut().enumerate() {
+        match templates::calibrate(&positives[i], &negatives[i], rate) {
+            Some((threshold, found)) => {
+                println!(
+                    "{}: {:.3} -> {:.3}, {:.0}% of {} cards of the set found, {} of other sets",
+                    template.code,
+                    template.threshold,
+                    threshold,
+                    found * 100.0,
+                    positives[i].len(),
+                    negatives[i].len(),
+                );
+                template.threshold = threshold;
+                template.search = Some(search);
+            },
+            None if negatives[i].is_empty() => println!("{}: no cards of other sets, kept {:.3}", template.code, template.threshold),
+            None => println!("{}: can't be told apart from other sets, kept {:.3}", template.code, template.threshold),
+        }
+    }
+
+    templates::save(std::path::Path::new(TEMPLATE_MANIFEST), &templates).unwrap();
+    println!("written to {}", TEMPLATE_MANIFEST);
+}
@@ -0,0 +1,131 @@
+use detection::*;
+use rayon::prelude::*;
+
+const POKEMONTCG_DATA: &str = "pokemontcg-data/";
+// Fewer cards can't tell the symbol apart from what they happen to share.
+const MIN_CARDS: usize = 3;
+// Calibrate it on labeled photos.
+const THRESHOLD: f32 = 0.15;
+// How far off (in warped card pixels) crops can be from each other.
+const SHIFT: u32 = 4;
+
+// The set symbol box, in 734x1024 warped card pixels, cropped out of every
+// image.
+fn crops(paths: &[&std::path::Path], area: &set_symbol_detection::SearchArea) -> Vec<image::GrayImage> {
+    paths
+        .par_iter()
+        .filter_map(|path| image::open(path).ok())
+        .map(|image| {
+            image
+                .resize_exact(734, 1024, image::imageops::FilterType::Triangle)
+                .crop_imm(area.x, area.y, area.width, area.height)
+                .to_luma8()
+        })
+        .collect()
+}
+
+// extract-templates [output directory] [--layout <era>]
+//
+// Builds a set symbol template for every set with enough dataset cards, out
+// of the set symbol box of the layout of the set's era (or of the given era,
+// one of wotc, ex, dp-hgss, bw-xy, sm, swsh, sv), and writes them next to a
+// manifest.json listing them. Sets without a known era are skipped.
+fn main() {
+    let mut output = std::path::PathBuf::from("templates/extracted/");
+    let mut forced = None;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--layout" {
+            let name = args.next().expect("--layout takes an era");
+            forced = Some(layout::ERAS.iter().copied().find(|e| e.name() == name).expect("unknown era"));
+        } else {
+            output = std::path::PathBuf::from(arg);
+        }
+    }
+
+    let mut metadata = metadata::MetadataSource::default();
+    if std::path::Path::new(POKEMONTCG_DATA).is_dir() {
+        let catalog = pokemontcg::load(std::path::Path::new(POKEMONTCG_DATA))
+            .expect("failed to read the pokemontcg.io data");
+        metadata.catalog = Some(catalog);
+    }
+
+    let config = load_hashing_config().unwrap_or_else(|e| panic!("failed to load {}: {}", HASHING_CONFIG, e));
+
+    let dataset = load_or_build_dataset("dataset/", "dataset.bin", &config, &metadata);
+
+    let mut sets = std::collections::BTreeMap::new();
+    for entry in dataset.entries.iter() {
+        if let Some(set) = &entry.metadata.set {
+            sets.entry(set.to_lowercase()).or_insert_with(Vec::new).push(entry);
+        }
+    }
+
+    std::fs::create_dir_all(&output).unwrap();
+
+    let mut extracted = vec![];
+    for (set, entries) in sets.iter() {
+        if entries.len() < MIN_CARDS {
+            println!("{}: only {} cards, skipped", set, entries.len());
+            continue;
+        }
+
+        let eras = entries
+            .iter()
+            .filter_map(|e| e.metadata.series.clone())
+            .collect::<std::collections::BTreeSet<_>>();
+        let era = forced.or_else(|| eras.iter().find_map(|s| layout::Era::of_series(s)));
+        let era = match era {
+            Some(era) => era,
+            None => {
+                println!("{}: unknown era, skipped", set);
+                continue;
+            },
+        };
+
+        let paths = entries.iter().map(|e| e.path.as_path()).collect::<Vec<_>>();
+
+        // The box with the most symbol in it, for eras with several.
+        let best = layout::Layout::of(era)
+            .set_symbols
+            .iter()
+            .filter_map(|rect| {
+                let area = rect.area(734, 1024);
+                let images = crops(&paths, &area);
+                if images.len() < MIN_CARDS {
+                    return None;
+                }
+
+                templates::extract(&images, SHIFT).map(|t| (area, t))
+            })
+            .max_by_key(|(_, t)| t.pixels().filter(|p| p[3] != 0).count());
+
+        let (area, image) = match best {
+            Some(best) => best,
+            None => {
+                println!("{}: nothing shared by its {} cards, skipped", set, entries.len());
+                continue;
+            },
+        };
+
+        let path = output.join(format!("{}.png", set));
+        image.save(&path).unwrap();
+        println!("{}: {}x{} from {} cards ({})", set, image.width(), image.height(), entries.len(), era.name());
+
+        extracted.push(templates::Template {
+            code: set.clone(),
+            path,
+            image: image::DynamicImage::ImageRgba8(image),
+            size: None,
+            threshold: THRESHOLD,
+            search: None,
+            areas: vec![area],
+            eras: eras.into_iter().collect(),
+        });
+    }
+
+    let manifest = output.join("manifest.json");
+    templates::save(&manifest, &extracted).unwrap();
+    println!("{} templates written to {:?}", extracted.len(), manifest);
+}
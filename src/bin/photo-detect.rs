@@ -2,61 +2,75 @@ use detection::*;
 use std::time::Instant;
 use image::GenericImage;
 
+// photo-detect [<name>=<directory>:<cache>:<priority> ...]
+//
+// Matches against the given catalogs, dataset/ and dataset.bin by default.
 fn main() {
-    let dataset = load_or_build_dataset("dataset/", "dataset.txt");
-    let templates = load_templates();
+    let specs = std::env::args().skip(1).collect::<Vec<_>>();
+    let sources = catalog_sources(&specs).unwrap_or_else(|e| panic!("{}", e));
+    let config = load_hashing_config().unwrap_or_else(|e| panic!("failed to load {}: {}", HASHING_CONFIG, e));
+
+    let dataset = load_catalogs(&sources, &config, &metadata::MetadataSource::default());
+    let options = ProcessingOptions {
+        glare_masking: true,
+        // e.g. CardFilter { regulation_marks: vec!["D".into(), "E".into()], ..Default::default() }
+        allowed: dataset.allowed(&metadata::CardFilter::default()),
+        // As written by calibrate-rejection.
+        rejection: load_rejection(&config).unwrap_or_else(|e| panic!("failed to load {}: {}", REJECTION_FILE, e)),
+        // As written by calibrate-confidence.
+        confidence: load_confidence(&config).unwrap_or_else(|e| panic!("failed to load {}: {}", CONFIDENCE_FILE, e)),
+        // Not real time, so it can afford to look harder.
+        set_search: set_symbol_detection::SearchRange::wide(),
+    };
+    let templates = load_templates(&dataset).unwrap_or_else(|e| panic!("failed to load templates: {}", e));
 
     let width = 1920;
     let height = 1080;
 
     let mut buffers = ProcessingBuffers::new(width, height);
     let mut frame = vec![];
-    frame.resize((width * height * 2) as usize, 0);
 
     let paths = std::fs::read_dir("images/canon-1080p/").unwrap();
     for path in paths {
         let filename = path.unwrap().path();
 
-        buffers.source_image = image::open(&filename).expect("failed to read image").resize(width, height, image::imageops::Nearest);
-
-        // I should be able to pass this luma8 image using traits
-        // instead of having to build a custom frame
-        let luma8 = buffers.source_image.to_luma8();
-        buffers.width = luma8.width();
-        buffers.height = luma8.height();
-
-        for y in 0 .. luma8.height() {
-            for x in 0 .. luma8.width() {
-                frame[(y * luma8.width() + x) as usize * 2] = luma8.get_pixel(x, y)[0];
-            }
-        }
+        let photo = image::open(&filename).expect("failed to read image");
+        buffers.load_photo(&photo, width, height, &mut frame);
 
         let mut processing = ProcessingPipeline {
             frame: &frame,
             buffers: &mut buffers,
+            options: &options,
         };
 
         let time = Instant::now();
 
         println!("processing");
-        let (times, best, detected_set) = process(&mut processing, &dataset, &templates);
+        let (times, best, set_matches) = process(&mut processing, &dataset, &templates);
 
-        println!("detected set: {:?}", detected_set);
+        for set_match in set_matches.iter().take(3) {
+            println!(
+                "set {}: {:.3} at {:?}, scale {:.3}, angle {:.1}",
+                set_match.set, set_match.score, set_match.location, set_match.scale, set_match.angle,
+            );
+        }
+        println!("glare area: {}px", processing.buffers.glare_area);
 
         // println!("[{:?}] sobel", times.sobel);
         // println!("[{:?}] border", times.border);
         // println!("[{:?}] hough", times.hough);
         // println!("[{:?}] corners", times.corners);
         // println!("[{:?}] perspective", times.perspective);
+        // println!("[{:?}] glare", times.glare);
         // println!("[{:?}] phash", times.phash);
         // println!("[{:?}] processed {:?}", time.elapsed(), filename);
 
         let stem = filename.file_stem().unwrap().to_str().unwrap();
-        save_debug_images(&processing, stem, best);
+        save_debug_images(&processing, stem, best, &dataset.config.regions);
     }
 }
 
-fn save_debug_images(processing: &ProcessingPipeline, stem: &str, best: Option<Vec<(&DatasetEntry, u32)>>) {
+fn save_debug_images(processing: &ProcessingPipeline, stem: &str, best: Option<Outcome>, regions: &[regions::Region]) {
     let width = processing.buffers.width;
     let height = processing.buffers.height;
 
@@ -156,10 +170,27 @@ fn save_debug_images(processing: &ProcessingPipeline, stem: &str, best: Option<V
     corners_img.save(format!("outputs/{}.04-corners.png", stem)).unwrap();
     processing.buffers.perspective_image.save(format!("outputs/{}.05-perspective.png", stem)).unwrap();
 
+    if !processing.buffers.glare.is_empty() {
+        let glare_width = image::GenericImageView::width(&processing.buffers.perspective_image);
+        let glare_height = image::GenericImageView::height(&processing.buffers.perspective_image);
+        let mut glare_img = image::DynamicImage::new_luma8(glare_width, glare_height).to_luma8();
+        for y in 0..glare_height {
+            for x in 0..glare_width {
+                let pixel = image::Luma([processing.buffers.glare[(y * glare_width + x) as usize] as u8 * 255]);
+                glare_img.put_pixel(x, y, pixel);
+            }
+        }
+        glare_img.save(format!("outputs/{}.05-glare.png", stem)).unwrap();
+    }
+
     match best {
-        Some(entries) => {
-            for (i, (entry, score)) in entries.iter().enumerate() {
-                let file = std::fs::File::open(&entry.path).unwrap();
+        Some(outcome) => {
+            if let Outcome::Unknown(_) = outcome {
+                println!("unknown card");
+            }
+
+            for (i, candidate) in outcome.candidates().iter().enumerate() {
+                let file = std::fs::File::open(&candidate.entry.path).unwrap();
                 image::io::Reader::new(std::io::BufReader::new(file))
                     .with_guessed_format()
                     .unwrap()
@@ -168,7 +199,10 @@ fn save_debug_images(processing: &ProcessingPipeline, stem: &str, best: Option<V
                     .save(format!("outputs/{}.06-best-{}.png", stem, i))
                     .unwrap();
 
-                println!("match{}: {:?} ({})", i, entry.path, score);
+                println!("match{}: {:?} [{}] ({}, score {:.3}, confidence {:.3})", i, candidate.entry.path, candidate.catalog.name, candidate.distance, candidate.score, candidate.confidence);
+                for (region, distance) in regions.iter().zip(candidate.region_distances.iter()) {
+                    println!("  {}: {}", region.name, distance);
+                }
             }
         },
         None => {},
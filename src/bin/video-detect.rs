@@ -5,9 +5,27 @@ use v4l::io::traits::CaptureStream;
 use v4l::video::Capture;
 use iced::Application;
 
+// video-detect [<name>=<directory>:<cache>:<priority> ...]
+//
+// Matches against the given catalogs, dataset/ and dataset.bin by default.
 fn main() {
-    let dataset = load_or_build_dataset("dataset/", "dataset.txt");
-    let templates = load_templates();
+    let specs = std::env::args().skip(1).collect::<Vec<_>>();
+    let sources = catalog_sources(&specs).unwrap_or_else(|e| panic!("{}", e));
+    let config = load_hashing_config().unwrap_or_else(|e| panic!("failed to load {}: {}", HASHING_CONFIG, e));
+
+    let dataset = load_catalogs(&sources, &config, &metadata::MetadataSource::default());
+    let options = ProcessingOptions {
+        glare_masking: true,
+        // e.g. CardFilter { regulation_marks: vec!["D".into(), "E".into()], ..Default::default() }
+        allowed: dataset.allowed(&metadata::CardFilter::default()),
+        // As written by calibrate-rejection.
+        rejection: load_rejection(&config).unwrap_or_else(|e| panic!("failed to load {}: {}", REJECTION_FILE, e)),
+        // As written by calibrate-confidence.
+        confidence: load_confidence(&config).unwrap_or_else(|e| panic!("failed to load {}: {}", CONFIDENCE_FILE, e)),
+        // Small enough to keep up with the camera.
+        set_search: set_symbol_detection::SearchRange::narrow(),
+    };
+    let templates = load_templates(&dataset).unwrap_or_else(|e| panic!("failed to load templates: {}", e));
 
     let (send, recv) = std::sync::mpsc::channel();
 
@@ -31,7 +49,7 @@ fn main() {
             let mut t = Instant::now();
             let (frame, meta) = stream.next().unwrap();
 
-            let mut processing = ProcessingPipeline { frame: &frame, buffers: &mut buffers };
+            let mut processing = ProcessingPipeline { frame: &frame, buffers: &mut buffers, options: &options };
 
             // I shouldn't care about RGB. Luma is all I need to calculate the img hash
             for y in 0..height as usize {
@@ -60,12 +78,13 @@ fn main() {
                 }
             }
 
-            let (times, best, detected_set) = process(&mut processing, &dataset, &templates);
+            let (times, best, set_matches) = process(&mut processing, &dataset, &templates);
+            let detected_set = set_matches.iter().find(|m| m.found()).map(|m| m.set);
 
-            match best {
-                Some(entries) => {
-                    send.send((entries[0].0.path.to_str().map(|x|x.to_string()), Some(entries[0].1), detected_set.map(|x|x.to_string()))).unwrap();
-                    println!("matches: {:?} ({}) | {:?}", entries[0].0.path, entries[0].1, detected_set);
+            match best.as_ref().and_then(|outcome| outcome.best()) {
+                Some(best) => {
+                    send.send((best.entry.path.to_str().map(|x|x.to_string()), Some(best.distance), detected_set.map(|x|x.to_string()))).unwrap();
+                    println!("matches: {:?} ({} {:?}) | {:?}", best.entry.path, best.distance, best.region_distances, detected_set);
                 }
                 None => {
                     // send.send((None, None, detected_set.map(|x|x.to_string())));
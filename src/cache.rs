@@ -0,0 +1,248 @@
+use crate::hashing;
+use crate::index;
+use crate::metadata;
+use crate::packed;
+use crate::DatasetEntry;
+use std::io::Write;
+
+// Layout, all integers little endian:
+//
+//   magic             8 bytes, "PTCGDSET"
+//   version           u32
+//   header            u32 length + UTF-8, HashingConfig::header()
+//   entry count       u32
+//   variants          u32, hash rows per entry (see DatasetIndex)
+//   card segments     u32 count + u32 bytes per hash
+//   region segments   u32 count + u32 bytes per hash
+//   entries           per entry, path as u32 length + UTF-8, u64 file size,
+//                     u64 modification time, every CardMetadata field as
+//                     u32 length + UTF-8, with u32::MAX for missing ones, and
+//                     colors as u32 length + bytes
+//   padding           zeros up to a multiple of 8
+//   card hashes       entries * variants * card stride u64 words
+//   region hashes     entries * variants * region stride u64 words
+const MAGIC: &[u8; 8] = b"PTCGDSET";
+const VERSION: u32 = 6;
+
+#[derive(Debug)]
+pub enum CacheError {
+    Io(std::io::Error),
+    NotACache,
+    UnsupportedVersion(u32),
+    ConfigMismatch { expected: String, found: String },
+    Corrupt(&'static str),
+}
+
+impl std::fmt::Display for CacheError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CacheError::Io(e) => write!(f, "{}", e),
+            CacheError::NotACache => write!(f, "not a dataset cache"),
+            CacheError::UnsupportedVersion(v) => write!(f, "unsupported cache version {} (expected {})", v, VERSION),
+            CacheError::ConfigMismatch { expected, found } => write!(f, "hashed with \"{}\", expected \"{}\"", found, expected),
+            CacheError::Corrupt(what) => write!(f, "corrupt cache: {}", what),
+        }
+    }
+}
+
+impl From<std::io::Error> for CacheError {
+    fn from(e: std::io::Error) -> Self {
+        CacheError::Io(e)
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize, what: &'static str) -> Result<&'a [u8], CacheError> {
+        if self.data.len() - self.position < n {
+            return Err(CacheError::Corrupt(what));
+        }
+
+        let bytes = &self.data[self.position .. self.position + n];
+        self.position += n;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self, what: &'static str) -> Result<u32, CacheError> {
+        let bytes = self.bytes(4, what)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn u64(&mut self, what: &'static str) -> Result<u64, CacheError> {
+        let b = self.bytes(8, what)?;
+        Ok(u64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))
+    }
+
+    fn byte_vec(&mut self, what: &'static str) -> Result<Vec<u8>, CacheError> {
+        let length = self.u32(what)? as usize;
+        Ok(self.bytes(length, what)?.to_vec())
+    }
+
+    fn string(&mut self, what: &'static str) -> Result<String, CacheError> {
+        String::from_utf8(self.byte_vec(what)?).map_err(|_| CacheError::Corrupt(what))
+    }
+
+    fn optional_string(&mut self, what: &'static str) -> Result<Option<String>, CacheError> {
+        let length = self.u32(what)?;
+        if length == u32::MAX {
+            return Ok(None);
+        }
+
+        let bytes = self.bytes(length as usize, what)?;
+        String::from_utf8(bytes.to_vec()).map(Some).map_err(|_| CacheError::Corrupt(what))
+    }
+
+    fn segments(&mut self, what: &'static str) -> Result<Vec<usize>, CacheError> {
+        let count = self.u32(what)?;
+        (0..count).map(|_| self.u32(what).map(|b| b as usize)).collect()
+    }
+
+    // Skips n words, returning where they start.
+    fn words(&mut self, n: usize, what: &'static str) -> Result<usize, CacheError> {
+        let start = self.position;
+        let length = n.checked_mul(8).ok_or(CacheError::Corrupt(what))?;
+
+        self.bytes(length, what)?;
+        Ok(start)
+    }
+}
+
+pub fn load(filename: &str, config: &hashing::HashingConfig) -> Result<index::DatasetIndex, CacheError> {
+    let file = std::fs::File::open(filename)?;
+    // SAFETY: caches are never modified in place, save writes a new file and
+    // renames it over the old one, so the mapped bytes can't change or go away
+    // while the map lives (short of someone else truncating the file).
+    let map = std::sync::Arc::new(unsafe { memmap2::Mmap::map(&file)? });
+    let mut reader = Reader { data: &map, position: 0 };
+
+    if reader.bytes(MAGIC.len(), "magic").map_err(|_| CacheError::NotACache)? != MAGIC {
+        return Err(CacheError::NotACache);
+    }
+
+    let version = reader.u32("version")?;
+    if version != VERSION {
+        return Err(CacheError::UnsupportedVersion(version));
+    }
+
+    let header = reader.string("header")?;
+    if header != config.header() {
+        return Err(CacheError::ConfigMismatch { expected: config.header(), found: header });
+    }
+
+    let count = reader.u32("entry count")? as usize;
+    let variants = reader.u32("variants")? as usize;
+    if variants == 0 {
+        return Err(CacheError::Corrupt("variants"));
+    }
+    let card_segments = reader.segments("card segments")?;
+    let region_segments = reader.segments("region segments")?;
+    if card_segments != config.card_segments() || region_segments != config.region_segments() {
+        return Err(CacheError::Corrupt("segments don't match the header"));
+    }
+
+    let mut entries = Vec::with_capacity(count.min(reader.data.len()));
+    for _ in 0..count {
+        let path = std::path::PathBuf::from(reader.string("path")?);
+        let file_size = reader.u64("file size")?;
+        let modified = reader.u64("modification time")?;
+
+        let mut card = metadata::CardMetadata::default();
+        for (field, _) in metadata::CardMetadata::default().fields().iter() {
+            if let Some(value) = reader.optional_string("metadata")? {
+                card.assign(field, &value);
+            }
+        }
+
+        let colors = reader.byte_vec("colors")?;
+
+        entries.push(DatasetEntry { path, file_size, modified, metadata: card, catalog: 0, colors });
+    }
+
+    let padding = (8 - reader.position % 8) % 8;
+    reader.bytes(padding, "padding")?;
+
+    let card_stride = card_segments.iter().map(|b| b.div_ceil(8)).sum::<usize>();
+    let region_stride = region_segments.iter().map(|b| b.div_ceil(8)).sum::<usize>();
+    let rows = count.checked_mul(variants).ok_or(CacheError::Corrupt("variants"))?;
+    let words = |stride: usize| rows.checked_mul(stride).ok_or(CacheError::Corrupt("variants"));
+    let card_offset = reader.words(words(card_stride)?, "card hashes")?;
+    let region_offset = reader.words(words(region_stride)?, "region hashes")?;
+
+    if reader.position != reader.data.len() {
+        return Err(CacheError::Corrupt("trailing data"));
+    }
+
+    // The hashes stay in the map.
+    let card = packed::PackedHashes::from_mapped(
+        config.hashes.iter().map(|spec| spec.weight).collect(),
+        &card_segments,
+        rows,
+        map.clone(),
+        card_offset,
+    ).ok_or(CacheError::Corrupt("card hashes"))?;
+
+    let regions = packed::PackedHashes::from_mapped(vec![], &region_segments, rows, map.clone(), region_offset)
+        .ok_or(CacheError::Corrupt("region hashes"))?;
+
+    Ok(index::DatasetIndex::from_packed(config.clone(), variants, entries, card, regions))
+}
+
+pub fn save(filename: &str, dataset: &index::DatasetIndex) -> std::io::Result<()> {
+    let mut data = vec![];
+
+    let put_u32 = |data: &mut Vec<u8>, v: u32| data.extend_from_slice(&v.to_le_bytes());
+    let put_string = |data: &mut Vec<u8>, s: &str| {
+        put_u32(data, s.len() as u32);
+        data.extend_from_slice(s.as_bytes());
+    };
+
+    data.extend_from_slice(MAGIC);
+    put_u32(&mut data, VERSION);
+    put_string(&mut data, &dataset.config.header());
+    put_u32(&mut data, dataset.len() as u32);
+    put_u32(&mut data, dataset.variants as u32);
+
+    for segments in [dataset.card.segment_bytes(), dataset.regions.segment_bytes()].iter() {
+        put_u32(&mut data, segments.len() as u32);
+        for &bytes in segments.iter() {
+            put_u32(&mut data, bytes as u32);
+        }
+    }
+
+    for entry in dataset.entries.iter() {
+        let path = entry.path.to_str().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{:?} is not valid UTF-8", entry.path))
+        })?;
+        put_string(&mut data, path);
+        data.extend_from_slice(&entry.file_size.to_le_bytes());
+        data.extend_from_slice(&entry.modified.to_le_bytes());
+
+        for (_, value) in entry.metadata.fields().iter() {
+            match value {
+                Some(value) => put_string(&mut data, value),
+                None => put_u32(&mut data, u32::MAX),
+            }
+        }
+
+        put_u32(&mut data, entry.colors.len() as u32);
+        data.extend_from_slice(&entry.colors);
+    }
+
+    while data.len() % 8 != 0 {
+        data.push(0);
+    }
+
+    for word in dataset.card.data().iter().chain(dataset.regions.data().iter()) {
+        data.extend_from_slice(&word.to_le_bytes());
+    }
+
+    // Write next to the cache and rename, so that an interrupted write never
+    // leaves a truncated cache behind.
+    let temporary = format!("{}.tmp", filename);
+    std::fs::File::create(&temporary)?.write_all(&data)?;
+    std::fs::rename(&temporary, filename)
+}
@@ -0,0 +1,184 @@
+const FEATURES: usize = 7;
+// Evidence field names, in order.
+const NAMES: [&str; FEATURES] = ["distance", "margin", "set", "other_set", "color", "glare", "sharpness"];
+
+// Everything known about how well a candidate matches a frame.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Evidence {
+    // Whole card distance over the maximum possible distance.
+    pub distance: f32,
+    // How much closer this candidate is than the closest other candidate, in
+    // the same units. Negative when another one is closer.
+    pub margin: f32,
+    // Set symbol template score for the candidate's set, from 0 (not found,
+    // or no template) to 1.
+    pub set: f32,
+    // Best set symbol template score among the other sets.
+    pub other_set: f32,
+    // Similarity between the frame's and the dataset image's colours, from 0
+    // to 1.
+    pub color: f32,
+    // Fraction of the card covered by glare.
+    pub glare: f32,
+    // See sharpness.
+    pub sharpness: f32,
+}
+
+impl Evidence {
+    fn features(&self) -> [f32; FEATURES] {
+        [self.distance, self.margin, self.set, self.other_set, self.color, self.glare, self.sharpness]
+    }
+}
+
+// Logistic regression over the evidence. The default weights are a guess;
+// fit them on labeled photos with calibrate-confidence (which saves them for
+// load_confidence) so that a confidence of 0.9 is right about 90% of the
+// time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ConfidenceModel {
+    // In Evidence field order.
+    pub weights: [f32; FEATURES],
+    pub bias: f32,
+}
+
+impl Default for ConfidenceModel {
+    fn default() -> Self {
+        ConfidenceModel {
+            weights: [-20.0, 20.0, 3.0, -3.0, 4.0, -2.0, 1.0],
+            bias: 0.0,
+        }
+    }
+}
+
+impl ConfidenceModel {
+    // From { "weights": { "distance": -20.0, "margin": 20.0, ... }, "bias": 0.0 }
+    // with a weight for every Evidence field.
+    pub fn from_json(json: &serde_json::Value) -> Result<Self, String> {
+        let number = |value: Option<&serde_json::Value>, name: &str| {
+            value.and_then(|v| v.as_f64()).map(|v| v as f32).ok_or_else(|| format!("{} must be a number", name))
+        };
+
+        let mut weights = [0.0; FEATURES];
+        for (weight, name) in weights.iter_mut().zip(NAMES.iter()) {
+            *weight = number(json.get("weights").and_then(|w| w.get(name)), name)?;
+        }
+
+        Ok(ConfidenceModel { weights, bias: number(json.get("bias"), "bias")? })
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let weights = NAMES
+            .iter()
+            .zip(self.weights.iter())
+            .map(|(name, weight)| (name.to_string(), serde_json::json!(weight)))
+            .collect::<serde_json::Map<_, _>>();
+
+        serde_json::json!({ "weights": weights, "bias": self.bias })
+    }
+
+    pub fn confidence(&self, evidence: &Evidence) -> f32 {
+        let z = self.weights
+            .iter()
+            .zip(evidence.features().iter())
+            .map(|(w, x)| w * x)
+            .sum::<f32>() + self.bias;
+
+        1.0 / (1.0 + (-z).exp())
+    }
+
+    // Maximum likelihood fit by gradient descent, with a little L2
+    // regularization so that separable samples don't blow up the weights.
+    pub fn fit(samples: &[(Evidence, bool)]) -> Self {
+        let mut model = ConfidenceModel { weights: [0.0; FEATURES], bias: 0.0 };
+        if samples.is_empty() {
+            return model;
+        }
+
+        let rate = 1.0;
+        let lambda = 0.001;
+        let n = samples.len() as f32;
+
+        for _ in 0..5000 {
+            let mut weights = [0.0; FEATURES];
+            let mut bias = 0.0;
+
+            for (evidence, right) in samples.iter() {
+                let error = model.confidence(evidence) - if *right { 1.0 } else { 0.0 };
+                for (g, x) in weights.iter_mut().zip(evidence.features().iter()) {
+                    *g += error * x;
+                }
+                bias += error;
+            }
+
+            for (w, g) in model.weights.iter_mut().zip(weights.iter()) {
+                *w -= rate * (g / n + lambda * *w);
+            }
+            model.bias -= rate * bias / n;
+        }
+
+        model
+    }
+}
+
+// How often candidates are right within each confidence bin, as (lowest
+// confidence in the bin, samples, mean confidence, fraction right). A
+// calibrated model has mean confidence close to the fraction right.
+pub fn reliability(model: &ConfidenceModel, samples: &[(Evidence, bool)], bins: usize) -> Vec<(f32, usize, f32, f32)> {
+    let mut totals = vec![(0, 0.0, 0); bins];
+
+    for (evidence, right) in samples.iter() {
+        let confidence = model.confidence(evidence);
+        let bin = ((confidence * bins as f32) as usize).min(bins - 1);
+
+        totals[bin].0 += 1;
+        totals[bin].1 += confidence;
+        totals[bin].2 += *right as usize;
+    }
+
+    totals
+        .into_iter()
+        .enumerate()
+        .filter(|(_, (count, _, _))| *count > 0)
+        .map(|(i, (count, confidence, right))| {
+            (i as f32 / bins as f32, count, confidence / count as f32, right as f32 / count as f32)
+        })
+        .collect()
+}
+
+// Colour similarity between two Fingerprint::colors, from 0 to 1.
+pub fn color_similarity(a: &[u8], b: &[u8]) -> f32 {
+    let n = a.len().min(b.len());
+    if n == 0 {
+        return 0.0;
+    }
+
+    let difference = a.iter().zip(b.iter()).map(|(a, b)| (*a as i32 - *b as i32).unsigned_abs()).sum::<u32>();
+    1.0 - difference as f32 / (255 * n) as f32
+}
+
+// Log variance of the Laplacian over every other pixel of the luma, roughly
+// from 0 for a blurry frame to 1 for a sharp one.
+pub fn sharpness(image: &image::DynamicImage) -> f32 {
+    let luma = image.to_luma8();
+    let (width, height) = luma.dimensions();
+    if width < 3 || height < 3 {
+        return 0.0;
+    }
+
+    let at = |x: u32, y: u32| luma.get_pixel(x, y)[0] as f32;
+
+    let mut sum = 0.0;
+    let mut squares = 0.0;
+    let mut count = 0.0;
+    for y in (1..height - 1).step_by(2) {
+        for x in (1..width - 1).step_by(2) {
+            let laplacian = at(x - 1, y) + at(x + 1, y) + at(x, y - 1) + at(x, y + 1) - 4.0 * at(x, y);
+            sum += laplacian;
+            squares += laplacian * laplacian;
+            count += 1.0;
+        }
+    }
+
+    let mean = sum / count;
+    (1.0 + squares / count - mean * mean).ln() / 10.0
+}
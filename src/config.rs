@@ -0,0 +1,64 @@
+use crate::hashing;
+
+// Small JSON files that every bin reads, so that settings and calibrated
+// values don't have to be patched into each of them. A missing file means the
+// defaults.
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::io::Error),
+    Invalid(String),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "{}", e),
+            ConfigError::Invalid(e) => write!(f, "invalid config: {}", e),
+        }
+    }
+}
+
+impl From<std::io::Error> for ConfigError {
+    fn from(e: std::io::Error) -> Self {
+        ConfigError::Io(e)
+    }
+}
+
+// None if there's no such file.
+pub fn read(path: &std::path::Path) -> Result<Option<serde_json::Value>, ConfigError> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => serde_json::from_str(&contents).map(Some).map_err(|e| ConfigError::Invalid(e.to_string())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn write(path: &std::path::Path, json: &serde_json::Value) -> std::io::Result<()> {
+    let json = serde_json::to_string_pretty(json)?;
+    std::fs::write(path, json + "\n")
+}
+
+// Calibrated values only hold for the hashing configuration they were
+// calibrated with, so they're written along with its distance_header, and
+// read back as None (with a note) if it changed since.
+pub fn read_calibrated(path: &std::path::Path, config: &hashing::HashingConfig) -> Result<Option<serde_json::Value>, ConfigError> {
+    let json = match read(path)? {
+        Some(json) => json,
+        None => return Ok(None),
+    };
+
+    match json.get("hashing").and_then(|h| h.as_str()) {
+        Some(header) if header == config.distance_header() => Ok(Some(json)),
+        Some(header) => {
+            println!("ignoring {:?}: calibrated for \"{}\", hashing with \"{}\"", path, header, config.distance_header());
+            Ok(None)
+        },
+        None => Err(ConfigError::Invalid("no hashing header".to_string())),
+    }
+}
+
+pub fn write_calibrated(path: &std::path::Path, config: &hashing::HashingConfig, mut json: serde_json::Value) -> std::io::Result<()> {
+    json["hashing"] = serde_json::json!(config.distance_header());
+    write(path, &json)
+}
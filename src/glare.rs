@@ -0,0 +1,119 @@
+use image::GenericImageView;
+
+// Specular highlights are bright and almost colourless, no matter what the
+// card underneath looks like. So are white borders and text boxes, though, so
+// glare is only the connected blobs of bright pixels that contain some
+// clipped ones and are big enough not to be print detail.
+pub fn calculate(image: &image::DynamicImage, glare: &mut Vec<bool>) -> u32 {
+    let value_threshold = 235u8;
+    let clipped_threshold = 250u8;
+    let saturation_threshold = 0.2f32;
+    let min_blob = 64;
+
+    let width = image.width();
+    let height = image.height();
+
+    // 0 is dark, 1 is bright, 2 is bright and clipped.
+    let mut brightness = vec![0u8; (width * height) as usize];
+    for y in 0..height {
+        for x in 0..width {
+            let p = image.get_pixel(x, y);
+            let max = p[0].max(p[1]).max(p[2]);
+            let min = p[0].min(p[1]).min(p[2]);
+
+            if max >= value_threshold && (max - min) as f32 <= saturation_threshold * max as f32 {
+                brightness[(y * width + x) as usize] = if max >= clipped_threshold { 2 } else { 1 };
+            }
+        }
+    }
+
+    glare.truncate(0);
+    glare.resize((width * height) as usize, false);
+
+    let mut visited = vec![false; brightness.len()];
+    let mut blob = vec![];
+    let mut stack = vec![];
+    let mut area = 0;
+
+    for start in 0..brightness.len() {
+        if brightness[start] == 0 || visited[start] {
+            continue;
+        }
+
+        blob.truncate(0);
+        let mut clipped = false;
+        visited[start] = true;
+        stack.push(start);
+
+        while let Some(i) = stack.pop() {
+            blob.push(i);
+            clipped |= brightness[i] == 2;
+
+            let (x, y) = ((i as u32 % width) as i64, (i as u32 / width) as i64);
+            for (nx, ny) in [(x - 1, y), (x + 1, y), (x, y - 1), (x, y + 1)].iter() {
+                if *nx < 0 || *ny < 0 || *nx >= width as i64 || *ny >= height as i64 {
+                    continue;
+                }
+
+                let n = (*ny as u32 * width + *nx as u32) as usize;
+                if brightness[n] != 0 && !visited[n] {
+                    visited[n] = true;
+                    stack.push(n);
+                }
+            }
+        }
+
+        if clipped && blob.len() >= min_blob {
+            for &i in blob.iter() {
+                glare[i] = true;
+            }
+            area += blob.len() as u32;
+        }
+    }
+
+    area
+}
+
+// Marks which cells of a columns x rows hash grid are mostly covered by glare.
+// The grid spans the (x0, y0, x1, y1) bounds of the image.
+pub fn cells(
+    glare: &[bool],
+    width: u32,
+    height: u32,
+    bounds: (u32, u32, u32, u32),
+    columns: u32,
+    rows: u32,
+    coverage: f32,
+) -> Vec<bool> {
+    let mut cells = vec![false; (columns * rows) as usize];
+    if glare.len() != (width * height) as usize {
+        return cells;
+    }
+
+    let (bx0, by0, bx1, by1) = bounds;
+    let bx1 = bx1.min(width);
+    let by1 = by1.min(height);
+
+    for r in 0..rows {
+        for c in 0..columns {
+            let x0 = bx0 + c * (bx1 - bx0) / columns;
+            let x1 = bx0 + (c + 1) * (bx1 - bx0) / columns;
+            let y0 = by0 + r * (by1 - by0) / rows;
+            let y1 = by0 + (r + 1) * (by1 - by0) / rows;
+
+            let mut count = 0;
+            for y in y0..y1 {
+                for x in x0..x1 {
+                    if glare[(y * width + x) as usize] {
+                        count += 1;
+                    }
+                }
+            }
+
+            let total = (x1 - x0) * (y1 - y0);
+            cells[(r * columns + c) as usize] = total > 0 && count as f32 >= coverage * total as f32;
+        }
+    }
+
+    cells
+}
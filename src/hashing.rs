@@ -0,0 +1,316 @@
+use crate::glare;
+use crate::layout;
+use crate::normalization;
+use crate::regions;
+
+// Bump this whenever preprocess_for_hash changes, so that caches built by an
+// older version are rebuilt instead of silently producing worse matches.
+pub const PREPROCESS_VERSION: u32 = 1;
+
+pub const CANONICAL_WIDTH: u32 = 367;
+pub const CANONICAL_HEIGHT: u32 = 512;
+pub const CANONICAL_CROP: u32 = 6;
+const BLUR: f32 = 0.75;
+const COLOR_GRID: u32 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HashKind {
+    Mean,
+    Gradient,
+    DoubleGradient,
+    Blockhash,
+    // Mean hash over the low frequencies of a DCT, a.k.a. pHash.
+    Dct,
+}
+
+pub const HASH_KINDS: [HashKind; 5] = [HashKind::Mean, HashKind::Gradient, HashKind::DoubleGradient, HashKind::Blockhash, HashKind::Dct];
+
+impl HashKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashKind::Mean => "mean",
+            HashKind::Gradient => "gradient",
+            HashKind::DoubleGradient => "double-gradient",
+            HashKind::Blockhash => "blockhash",
+            HashKind::Dct => "dct",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HashSpec {
+    pub kind: HashKind,
+    pub width: u32,
+    pub height: u32,
+    // Multiplies this hash's distance when combining several hashes.
+    pub weight: u32,
+}
+
+impl HashSpec {
+    pub fn new(kind: HashKind, width: u32, height: u32) -> Self {
+        HashSpec { kind, width, height, weight: 1 }
+    }
+
+    pub fn name(&self) -> String {
+        format!("{}:{}x{}", self.kind.name(), self.width, self.height)
+    }
+
+    // From { "kind": "dct", "width": 8, "height": 8, "weight": 2 }, where the
+    // weight is optional.
+    pub fn from_json(json: &serde_json::Value) -> Result<Self, String> {
+        let kind = json
+            .get("kind")
+            .and_then(|k| k.as_str())
+            .and_then(|k| HASH_KINDS.iter().copied().find(|kind| kind.name() == k))
+            .ok_or_else(|| {
+                let kinds = HASH_KINDS.iter().map(HashKind::name).collect::<Vec<_>>();
+                format!("hash kind must be one of {}", kinds.join(", "))
+            })?;
+
+        let number = |name: &str| {
+            json.get(name)
+                .and_then(|n| n.as_u64())
+                .filter(|&n| n > 0 && n <= u32::MAX as u64)
+                .map(|n| n as u32)
+                .ok_or_else(|| format!("hash {} must be a positive number", name))
+        };
+
+        let mut spec = HashSpec::new(kind, number("width")?, number("height")?);
+        if json.get("weight").is_some() {
+            spec.weight = number("weight")?;
+        }
+
+        Ok(spec)
+    }
+
+    pub fn hasher(&self) -> img_hash::Hasher {
+        let config = img_hash::HasherConfig::new().hash_size(self.width, self.height);
+
+        match self.kind {
+            HashKind::Mean => config.hash_alg(img_hash::HashAlg::Mean),
+            HashKind::Gradient => config.hash_alg(img_hash::HashAlg::Gradient),
+            HashKind::DoubleGradient => config.hash_alg(img_hash::HashAlg::DoubleGradient),
+            HashKind::Blockhash => config.hash_alg(img_hash::HashAlg::Blockhash),
+            HashKind::Dct => config.hash_alg(img_hash::HashAlg::Mean).preproc_dct(),
+        }.to_hasher()
+    }
+
+    // Size of the hashes this spec produces, as found by hashing a blank
+    // image, since it depends on the algorithm.
+    pub fn bytes(&self) -> usize {
+        let blank = image::DynamicImage::new_luma8(self.width.max(1) * 4, self.height.max(1) * 4);
+        self.hasher().hash_image(&blank).as_bytes().len()
+    }
+
+    // The columns x rows grid of image cells that the hash bits map to, in
+    // order. Hashes whose bits aren't spatial (DCT) or that interleave two
+    // grids (double gradient) have none.
+    pub fn grid(&self) -> Option<(u32, u32)> {
+        match self.kind {
+            HashKind::Mean | HashKind::Gradient | HashKind::Blockhash => Some((self.width, self.height)),
+            HashKind::DoubleGradient | HashKind::Dct => None,
+        }
+    }
+}
+
+// All the hashes computed for one card image.
+pub struct Fingerprint {
+    // One hash per HashingConfig::hashes spec, over the whole card.
+    pub card: Vec<img_hash::ImageHash>,
+    // One hash per HashingConfig::regions entry.
+    pub regions: Vec<img_hash::ImageHash>,
+    // Mean colour of each cell of a coarse grid, as RGB bytes. Not hashed,
+    // only used as evidence for the confidence model.
+    pub colors: Vec<u8>,
+}
+
+// Everything that affects the value of a hash. Dataset and query hashes are
+// only comparable if they were computed with the same configuration.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HashingConfig {
+    pub normalization: normalization::Normalization,
+    // Every dataset entry stores one hash per spec, in this order.
+    pub hashes: Vec<HashSpec>,
+    pub regions: Vec<regions::Region>,
+    pub region_hash: HashSpec,
+}
+
+impl Default for HashingConfig {
+    fn default() -> Self {
+        HashingConfig {
+            normalization: normalization::Normalization::default(),
+            hashes: vec![HashSpec::new(HashKind::Gradient, 16, 16)],
+            regions: regions::default_regions(),
+            region_hash: HashSpec::new(HashKind::Gradient, 8, 8),
+        }
+    }
+}
+
+impl HashingConfig {
+    // Reads a configuration like
+    //
+    //   { "normalization": { "white_balance": true, "equalization": "clahe" },
+    //     "hashes": [{ "kind": "dct", "width": 8, "height": 8, "weight": 2 },
+    //                { "kind": "gradient", "width": 16, "height": 16 }],
+    //     "region_hash": { "kind": "gradient", "width": 8, "height": 8 },
+    //     "layout": "swsh" }
+    //
+    // where kinds are mean, gradient, double-gradient, blockhash or dct, the
+    // layout is the era (see layout::Era::name) whose Layout::regions get
+    // hashed instead of regions::default_regions, and missing fields keep
+    // their default.
+    pub fn from_json(json: &serde_json::Value) -> Result<Self, String> {
+        let mut config = HashingConfig::default();
+
+        if let Some(normalization) = json.get("normalization") {
+            config.normalization = normalization::Normalization::from_json(normalization)?;
+        }
+
+        if let Some(hashes) = json.get("hashes") {
+            config.hashes = hashes
+                .as_array()
+                .filter(|hashes| !hashes.is_empty())
+                .ok_or("hashes must be a non empty array")?
+                .iter()
+                .map(HashSpec::from_json)
+                .collect::<Result<_, _>>()?;
+        }
+
+        if let Some(region_hash) = json.get("region_hash") {
+            config.region_hash = HashSpec::from_json(region_hash)?;
+        }
+
+        if let Some(name) = json.get("layout") {
+            let era = name
+                .as_str()
+                .and_then(|name| layout::ERAS.iter().copied().find(|era| era.name() == name))
+                .ok_or_else(|| {
+                    let eras = layout::ERAS.iter().map(layout::Era::name).collect::<Vec<_>>();
+                    format!("layout must be one of {}", eras.join(", "))
+                })?;
+
+            config.regions = layout::Layout::of(era).regions();
+        }
+
+        Ok(config)
+    }
+
+    // Written as the first line of the dataset cache. A cache with a different
+    // header was hashed with a different configuration and must be rebuilt.
+    pub fn header(&self) -> String {
+        format!(
+            "# preprocess={} normalization={} hashes={} regions={} region-hash={}",
+            PREPROCESS_VERSION,
+            self.normalization.name(),
+            self.hashes.iter().map(HashSpec::name).collect::<Vec<_>>().join(","),
+            self.regions.iter().map(regions::Region::header).collect::<Vec<_>>().join(";"),
+            self.region_hash.name(),
+        )
+    }
+
+    // Like header, plus the weights, which don't change any hash but do
+    // change the distances that thresholds are calibrated on.
+    pub fn distance_header(&self) -> String {
+        let weights = self.hashes.iter().map(|spec| spec.weight.to_string()).collect::<Vec<_>>();
+        format!("{} weights={}", self.header(), weights.join(","))
+    }
+
+    // Bytes per hash of a Fingerprint's card hashes and region hashes.
+    pub fn card_segments(&self) -> Vec<usize> {
+        self.hashes.iter().map(HashSpec::bytes).collect()
+    }
+
+    pub fn region_segments(&self) -> Vec<usize> {
+        vec![self.region_hash.bytes(); self.regions.len()]
+    }
+
+    // The part of a full card image, in its own pixel coordinates, that
+    // survives the crop in preprocess_for_hash.
+    pub fn crop_bounds(&self, width: u32, height: u32) -> (u32, u32, u32, u32) {
+        let x = width * CANONICAL_CROP / CANONICAL_WIDTH;
+        let y = height * CANONICAL_CROP / CANONICAL_HEIGHT;
+
+        (x, y, width - x, height - y)
+    }
+
+    pub fn hash(&self, image: &image::DynamicImage) -> Fingerprint {
+        let preprocessed = preprocess_for_hash(image, self);
+        let region_hasher = self.region_hash.hasher();
+
+        Fingerprint {
+            card: self.hashes
+                .iter()
+                .map(|spec| spec.hasher().hash_image(&preprocessed))
+                .collect(),
+            regions: self.regions
+                .iter()
+                .map(|region| region_hasher.hash_image(&region_image(&preprocessed, region)))
+                .collect(),
+            colors: preprocessed
+                .resize_exact(COLOR_GRID, COLOR_GRID, image::imageops::FilterType::Triangle)
+                .to_rgb8()
+                .into_raw(),
+        }
+    }
+
+    // Votes between the whole card distance and every region distance, all
+    // normalized by their number of bits. Lower is better.
+    pub fn score(&self, query: &Fingerprint, distance: u32, region_distances: &[u32]) -> f32 {
+        let card_bits: u32 = self.hashes
+            .iter()
+            .zip(query.card.iter())
+            .map(|(spec, hash)| spec.weight * hash.as_bytes().len() as u32 * 8)
+            .sum();
+
+        let mut distances = vec![(distance as f32 / card_bits.max(1) as f32, 1.0)];
+        for ((region, distance), hash) in self.regions.iter().zip(region_distances.iter()).zip(query.regions.iter()) {
+            let bits = hash.as_bytes().len() as f32 * 8.0;
+            distances.push((*distance as f32 / bits.max(1.0), region.weight));
+        }
+
+        regions::vote(&distances)
+    }
+
+    // Per hash spec, which of its cells are covered by glare on a full card
+    // image of the given size.
+    pub fn glare_cells(&self, glare: &[bool], width: u32, height: u32) -> Vec<Option<Vec<bool>>> {
+        let bounds = self.crop_bounds(width, height);
+
+        self.hashes
+            .iter()
+            .map(|spec| spec.grid().map(|(columns, rows)| glare::cells(glare, width, height, bounds, columns, rows, 0.25)))
+            .collect()
+    }
+}
+
+// Both dataset scans and warped camera frames go through this before being
+// hashed, so that differences in resolution, borders and sharpness don't show
+// up as hash distance.
+pub fn preprocess_for_hash(image: &image::DynamicImage, config: &HashingConfig) -> image::DynamicImage {
+    let resized = image
+        .resize_exact(CANONICAL_WIDTH, CANONICAL_HEIGHT, image::imageops::FilterType::Triangle)
+        .crop_imm(
+            CANONICAL_CROP,
+            CANONICAL_CROP,
+            CANONICAL_WIDTH - 2 * CANONICAL_CROP,
+            CANONICAL_HEIGHT - 2 * CANONICAL_CROP,
+        );
+
+    normalization::apply(&resized, &config.normalization).blur(BLUR)
+}
+
+// Crops a region out of an image produced by preprocess_for_hash.
+fn region_image(preprocessed: &image::DynamicImage, region: &regions::Region) -> image::DynamicImage {
+    let width = CANONICAL_WIDTH - 2 * CANONICAL_CROP;
+    let height = CANONICAL_HEIGHT - 2 * CANONICAL_CROP;
+
+    let to_x = |x: f64| ((x * CANONICAL_WIDTH as f64) as u32).saturating_sub(CANONICAL_CROP).min(width - 1);
+    let to_y = |y: f64| ((y * CANONICAL_HEIGHT as f64) as u32).saturating_sub(CANONICAL_CROP).min(height - 1);
+
+    let x0 = to_x(region.x);
+    let y0 = to_y(region.y);
+    let x1 = to_x(region.x + region.width).max(x0 + 1);
+    let y1 = to_y(region.y + region.height).max(y0 + 1);
+
+    preprocessed.crop_imm(x0, y0, x1 - x0, y1 - y0)
+}
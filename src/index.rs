@@ -0,0 +1,289 @@
+use crate::hashing;
+use crate::metadata;
+use crate::packed;
+use crate::DatasetEntry;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Search {
+    // Brute force popcount scan. Trivially correct and, up to a few hundred
+    // thousand entries, usually the fastest option.
+    Linear,
+    BkTree,
+}
+
+#[derive(Clone, Debug)]
+pub struct Catalog {
+    pub name: String,
+    pub priority: i32,
+}
+
+struct Node {
+    row: usize,
+    // (distance to this node's row, child node)
+    children: Vec<(u32, usize)>,
+}
+
+// The dataset entries together with their packed hashes. Every entry has
+// `variants` rows in `card` and `regions`, the hashes of the image itself
+// followed by those of its augmented variants, so row i belongs to
+// entries[i / variants]. An entry's distance is the smallest of its rows'.
+//
+// Searches run over rows and keep the first row found for each entry. Asking
+// for k * variants rows always yields at least k entries, and since rows come
+// sorted by distance, the first k of those are the k nearest entries.
+//
+// The BK-tree works because the combined card distance is a sum of weighted
+// Hamming distances, so it is a metric and the triangle inequality lets whole
+// subtrees be skipped while still returning exact results. It's only built
+// the first time something searches it.
+pub struct DatasetIndex {
+    pub entries: Vec<DatasetEntry>,
+    pub config: hashing::HashingConfig,
+    pub card: packed::PackedHashes,
+    pub regions: packed::PackedHashes,
+    pub search: Search,
+    pub catalogs: Vec<Catalog>,
+    pub variants: usize,
+    nodes: std::sync::OnceLock<Vec<Node>>,
+}
+
+impl DatasetIndex {
+    // Every entry must come with the same number of fingerprints, the
+    // original image's first.
+    pub fn new(config: hashing::HashingConfig, variants: usize, dataset: Vec<(DatasetEntry, Vec<hashing::Fingerprint>)>) -> Self {
+        let mut card = packed::PackedHashes::new(config.hashes.iter().map(|spec| spec.weight).collect(), &config.card_segments());
+        let mut regions = packed::PackedHashes::new(vec![], &config.region_segments());
+        let mut entries = Vec::with_capacity(dataset.len());
+
+        for (entry, fingerprints) in dataset.into_iter() {
+            assert_eq!(fingerprints.len(), variants, "wrong number of fingerprints for {:?}", entry.path);
+
+            for fingerprint in fingerprints.iter() {
+                card.push(&fingerprint.card);
+                regions.push(&fingerprint.regions);
+            }
+            entries.push(entry);
+        }
+
+        DatasetIndex::from_packed(config, variants, entries, card, regions)
+    }
+
+    pub fn from_packed(
+        config: hashing::HashingConfig,
+        variants: usize,
+        entries: Vec<DatasetEntry>,
+        card: packed::PackedHashes,
+        regions: packed::PackedHashes,
+    ) -> Self {
+        let catalogs = vec![Catalog { name: "default".to_string(), priority: 0 }];
+        DatasetIndex {
+            entries,
+            config,
+            card,
+            regions,
+            search: Search::Linear,
+            catalogs,
+            variants,
+            nodes: std::sync::OnceLock::new(),
+        }
+    }
+
+    // Combines indexes hashed with the same config into one, recording which
+    // catalog every entry came from. Searches break distance ties by row, so
+    // higher priority catalogs are placed first. Catalogs with fewer variants
+    // repeat their last row, which doesn't change any entry's distance.
+    pub fn merge(config: hashing::HashingConfig, mut catalogs: Vec<(Catalog, DatasetIndex)>) -> Self {
+        catalogs.sort_by_key(|(catalog, _)| std::cmp::Reverse(catalog.priority));
+        let variants = catalogs.iter().map(|(_, index)| index.variants).max().unwrap_or(1);
+
+        let mut card = packed::PackedHashes::new(config.hashes.iter().map(|spec| spec.weight).collect(), &config.card_segments());
+        let mut regions = packed::PackedHashes::new(vec![], &config.region_segments());
+        let mut entries = vec![];
+        let mut merged = vec![];
+
+        for (i, (catalog, index)) in catalogs.into_iter().enumerate() {
+            let own = index.variants;
+            let rows = (0..index.len())
+                .flat_map(|entry| (0..variants).map(move |v| entry * own + v.min(own - 1)))
+                .collect::<Vec<_>>();
+
+            let extended = card.extend(&index.card, &rows) && regions.extend(&index.regions, &rows);
+            assert!(extended, "catalog {} was hashed with different hash sizes", catalog.name);
+
+            entries.extend(index.entries.into_iter().map(|mut entry| {
+                entry.catalog = i;
+                entry
+            }));
+            merged.push(catalog);
+        }
+
+        let mut index = DatasetIndex::from_packed(config, variants, entries, card, regions);
+        index.catalogs = merged;
+        index
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn fingerprints(&self, entry: usize) -> Vec<hashing::Fingerprint> {
+        self.rows(entry)
+            .map(|row| hashing::Fingerprint {
+                card: self.card.unpack(row),
+                regions: self.regions.unpack(row),
+                colors: self.entries[entry].colors.clone(),
+            })
+            .collect()
+    }
+
+    pub fn rows(&self, entry: usize) -> std::ops::Range<usize> {
+        entry * self.variants .. (entry + 1) * self.variants
+    }
+
+    // Turns rows sorted by distance into at most k entries sorted by distance,
+    // each with its nearest row's distance.
+    pub fn entries_of(&self, rows: Vec<(usize, u32)>, k: usize) -> Vec<(usize, u32)> {
+        let mut seen = std::collections::HashSet::new();
+
+        rows.into_iter()
+            .map(|(row, d)| (row / self.variants, d))
+            .filter(|&(entry, _)| seen.insert(entry))
+            .take(k)
+            .collect()
+    }
+
+    // Per region distances to an entry, each the smallest across its rows.
+    pub fn segment_distances(&self, query: &[u64], entry: usize) -> Vec<u32> {
+        self.rows(entry)
+            .map(|row| self.regions.segment_distances(query, row))
+            .fold(None, |best: Option<Vec<u32>>, distances| match best {
+                Some(best) => Some(best.iter().zip(distances.iter()).map(|(a, b)| *a.min(b)).collect()),
+                None => Some(distances),
+            })
+            .unwrap_or_default()
+    }
+
+    fn tree(&self) -> &[Node] {
+        self.nodes.get_or_init(|| {
+            let mut nodes: Vec<Node> = vec![];
+
+            for row in 0..self.card.len() {
+                if nodes.is_empty() {
+                    nodes.push(Node { row, children: vec![] });
+                    continue;
+                }
+
+                let mut current = 0;
+                loop {
+                    let d = self.card.distance(self.card.row(row), nodes[current].row);
+
+                    match nodes[current].children.iter().find(|(edge, _)| *edge == d) {
+                        Some(&(_, child)) => current = child,
+                        None => {
+                            let child = nodes.len();
+                            nodes.push(Node { row, children: vec![] });
+                            nodes[current].children.push((d, child));
+                            break;
+                        }
+                    }
+                }
+            }
+
+            nodes
+        })
+    }
+
+    // One flag per row, telling whether its entry passes the filter, or None
+    // when the filter allows everything. Meant to be computed once and reused
+    // for every query.
+    pub fn allowed(&self, filter: &metadata::CardFilter) -> Option<Vec<bool>> {
+        if filter.is_empty() {
+            return None;
+        }
+
+        Some(
+            self.entries
+                .iter()
+                .flat_map(|e| std::iter::repeat(filter.matches(&e.metadata)).take(self.variants))
+                .collect()
+        )
+    }
+
+    // The k allowed entries closest to a packed card query, as (entry,
+    // distance) sorted by distance.
+    pub fn nearest(&self, query: &[u64], k: usize, allowed: Option<&[bool]>) -> Vec<(usize, u32)> {
+        let rows = match self.search {
+            Search::Linear => self.card.nearest(query, k * self.variants, allowed),
+            Search::BkTree => self.tree_nearest(query, k * self.variants, allowed),
+        };
+
+        self.entries_of(rows, k)
+    }
+
+    // The k allowed rows closest to the query. Disallowed nodes are still
+    // visited, since their subtrees may hold allowed rows, but never end up in
+    // the results.
+    fn tree_nearest(&self, query: &[u64], k: usize, allowed: Option<&[bool]>) -> Vec<(usize, u32)> {
+        let mut best: std::collections::BinaryHeap<(u32, usize)> = std::collections::BinaryHeap::new();
+        let nodes = self.tree();
+        if k == 0 || nodes.is_empty() {
+            return vec![];
+        }
+
+        let mut pending = vec![0];
+        while let Some(current) = pending.pop() {
+            let node = &nodes[current];
+            let d = self.card.distance(query, node.row);
+
+            let candidate = allowed.map(|a| a[node.row]).unwrap_or(true);
+            if candidate && best.len() < k {
+                best.push((d, node.row));
+            } else if candidate && (d, node.row) < *best.peek().unwrap() {
+                best.pop();
+                best.push((d, node.row));
+            }
+
+            let radius = if best.len() < k { u32::MAX } else { best.peek().unwrap().0 };
+            for &(edge, child) in node.children.iter() {
+                if edge.saturating_add(radius) >= d && d.saturating_add(radius) >= edge {
+                    pending.push(child);
+                }
+            }
+        }
+
+        best.into_sorted_vec().into_iter().map(|(d, row)| (row, d)).collect()
+    }
+
+    // Every entry within radius of a packed card query, as (entry, distance)
+    // sorted by distance.
+    pub fn within(&self, query: &[u64], radius: u32) -> Vec<(usize, u32)> {
+        let mut found = vec![];
+        let nodes = self.tree();
+        if nodes.is_empty() {
+            return found;
+        }
+
+        let mut pending = vec![0];
+        while let Some(current) = pending.pop() {
+            let node = &nodes[current];
+            let d = self.card.distance(query, node.row);
+
+            if d <= radius {
+                found.push((node.row, d));
+            }
+
+            for &(edge, child) in node.children.iter() {
+                if edge.saturating_add(radius) >= d && d.saturating_add(radius) >= edge {
+                    pending.push(child);
+                }
+            }
+        }
+
+        found.sort_by_key(|&(row, d)| (d, row));
+        self.entries_of(found, usize::MAX)
+    }
+}
@@ -0,0 +1,196 @@
+use crate::metadata;
+use crate::regions;
+use crate::set_symbol_detection;
+
+// A box in normalized card coordinates (0.0 - 1.0 over the full card,
+// borders included), like regions::Region.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rect {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+impl Rect {
+    pub const fn new(x: f64, y: f64, width: f64, height: f64) -> Self {
+        Rect { x, y, width, height }
+    }
+
+    // In pixels of a card warped to width x height.
+    pub fn area(&self, width: u32, height: u32) -> set_symbol_detection::SearchArea {
+        set_symbol_detection::SearchArea {
+            x: (self.x * width as f64).round() as u32,
+            y: (self.y * height as f64).round() as u32,
+            width: (self.width * width as f64).round() as u32,
+            height: (self.height * height as f64).round() as u32,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Era {
+    Wotc,
+    Ex,
+    DpHgss,
+    BwXy,
+    Sm,
+    Swsh,
+    Sv,
+}
+
+pub const ERAS: [Era; 7] = [Era::Wotc, Era::Ex, Era::DpHgss, Era::BwXy, Era::Sm, Era::Swsh, Era::Sv];
+
+impl Era {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Era::Wotc => "wotc",
+            Era::Ex => "ex",
+            Era::DpHgss => "dp-hgss",
+            Era::BwXy => "bw-xy",
+            Era::Sm => "sm",
+            Era::Swsh => "swsh",
+            Era::Sv => "sv",
+        }
+    }
+
+    // From a CardMetadata::series, as named by pokemontcg.io.
+    pub fn of_series(series: &str) -> Option<Era> {
+        let era = match series.to_ascii_lowercase().as_str() {
+            "base" | "gym" | "neo" | "e-card" | "legendary collection" | "other" => Era::Wotc,
+            "ex" | "pop" => Era::Ex,
+            "diamond & pearl" | "platinum" | "heartgold & soulsilver" | "call of legends" => Era::DpHgss,
+            "black & white" | "xy" => Era::BwXy,
+            "sun & moon" => Era::Sm,
+            "sword & shield" => Era::Swsh,
+            "scarlet & violet" => Era::Sv,
+            _ => return None,
+        };
+
+        Some(era)
+    }
+
+    pub fn of(metadata: &metadata::CardMetadata) -> Option<Era> {
+        metadata.series.as_deref().and_then(Era::of_series)
+    }
+}
+
+// Where things are printed on the cards of an era. Rough boxes, somewhat
+// bigger than what they hold so that imperfect warps still contain it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Layout {
+    pub era: Era,
+    // Several for eras whose symbol moved around between sets.
+    pub set_symbols: Vec<Rect>,
+    // Rough height of the printed set symbol itself over the card's height,
+    // which SVG templates are rasterized to. Unlike the boxes, it isn't
+    // padded; check it against scans if an era's symbols match poorly.
+    pub symbol_height: f64,
+    pub collector_number: Rect,
+    pub artwork: Rect,
+    pub name_bar: Rect,
+    pub regulation_mark: Option<Rect>,
+}
+
+impl Layout {
+    pub fn of(era: Era) -> Self {
+        match era {
+            Era::Wotc => Layout {
+                era,
+                set_symbols: vec![Rect::new(0.80, 0.49, 0.18, 0.12)],
+                symbol_height: 0.040,
+                collector_number: Rect::new(0.76, 0.91, 0.20, 0.06),
+                artwork: Rect::new(0.10, 0.10, 0.80, 0.40),
+                name_bar: Rect::new(0.06, 0.03, 0.88, 0.07),
+                regulation_mark: None,
+            },
+            Era::Ex => Layout {
+                era,
+                set_symbols: vec![Rect::new(0.80, 0.49, 0.18, 0.12), Rect::new(0.82, 0.83, 0.18, 0.17)],
+                symbol_height: 0.035,
+                collector_number: Rect::new(0.76, 0.92, 0.22, 0.06),
+                artwork: Rect::new(0.09, 0.10, 0.82, 0.40),
+                name_bar: Rect::new(0.06, 0.03, 0.88, 0.07),
+                regulation_mark: None,
+            },
+            Era::DpHgss => Layout {
+                era,
+                set_symbols: vec![Rect::new(0.82, 0.83, 0.18, 0.17)],
+                symbol_height: 0.035,
+                collector_number: Rect::new(0.76, 0.92, 0.22, 0.06),
+                artwork: Rect::new(0.08, 0.10, 0.84, 0.41),
+                name_bar: Rect::new(0.06, 0.03, 0.88, 0.07),
+                regulation_mark: None,
+            },
+            Era::BwXy => Layout {
+                era,
+                set_symbols: vec![Rect::new(0.82, 0.83, 0.18, 0.17)],
+                symbol_height: 0.033,
+                collector_number: Rect::new(0.72, 0.92, 0.26, 0.06),
+                artwork: Rect::new(0.08, 0.10, 0.84, 0.41),
+                name_bar: Rect::new(0.06, 0.03, 0.88, 0.07),
+                regulation_mark: None,
+            },
+            Era::Sm => Layout {
+                era,
+                set_symbols: vec![Rect::new(0.01, 0.88, 0.16, 0.10)],
+                symbol_height: 0.035,
+                collector_number: Rect::new(0.06, 0.93, 0.26, 0.05),
+                artwork: Rect::new(0.08, 0.10, 0.84, 0.41),
+                name_bar: Rect::new(0.06, 0.03, 0.88, 0.07),
+                regulation_mark: None,
+            },
+            Era::Swsh => Layout {
+                era,
+                set_symbols: vec![Rect::new(0.01, 0.88, 0.16, 0.10)],
+                symbol_height: 0.035,
+                collector_number: Rect::new(0.06, 0.93, 0.26, 0.05),
+                artwork: Rect::new(0.08, 0.10, 0.84, 0.42),
+                name_bar: Rect::new(0.06, 0.03, 0.88, 0.07),
+                regulation_mark: Some(Rect::new(0.03, 0.93, 0.06, 0.05)),
+            },
+            Era::Sv => Layout {
+                era,
+                set_symbols: vec![Rect::new(0.01, 0.90, 0.16, 0.09)],
+                symbol_height: 0.030,
+                collector_number: Rect::new(0.06, 0.94, 0.26, 0.05),
+                artwork: Rect::new(0.06, 0.10, 0.88, 0.44),
+                name_bar: Rect::new(0.06, 0.03, 0.88, 0.07),
+                regulation_mark: Some(Rect::new(0.03, 0.94, 0.06, 0.05)),
+            },
+        }
+    }
+
+    // Hashing regions following this layout, weighted like
+    // regions::default_regions.
+    pub fn regions(&self) -> Vec<regions::Region> {
+        let region = |name: &str, r: &Rect, weight: f32| regions::Region::new(name, r.x, r.y, r.width, r.height, weight);
+
+        vec![
+            region("name", &self.name_bar, 1.0),
+            region("artwork", &self.artwork, 2.0),
+            region("bottom", &self.collector_number, 0.5),
+        ]
+    }
+}
+
+// Every profile, oldest first.
+pub fn profiles() -> Vec<Layout> {
+    ERAS.iter().map(|&era| Layout::of(era)).collect()
+}
+
+// Set symbol search areas on a card warped to width x height, for every
+// given layout, without duplicates.
+pub fn set_symbol_areas<'a, I>(layouts: I, width: u32, height: u32) -> Vec<set_symbol_detection::SearchArea> where I: IntoIterator<Item = &'a Layout> {
+    let mut areas = vec![];
+    for layout in layouts {
+        for rect in layout.set_symbols.iter() {
+            let area = rect.area(width, height);
+            if !areas.contains(&area) {
+                areas.push(area);
+            }
+        }
+    }
+
+    areas
+}
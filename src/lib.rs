@@ -1,7 +1,5 @@
 use image::GenericImageView;
 use image::GenericImage;
-use std::io::BufRead;
-use std::io::Write;
 use std::time::Instant;
 use rayon::prelude::*;
 
@@ -12,11 +10,160 @@ pub mod lines;
 pub mod corners;
 pub mod perspective;
 pub mod set_symbol_detection;
+pub mod layout;
+pub mod templates;
+pub mod glare;
+pub mod normalization;
+pub mod hashing;
+pub mod augment;
+pub mod regions;
+pub mod packed;
+pub mod index;
+pub mod cache;
+pub mod metadata;
+pub mod rejection;
+pub mod confidence;
+pub mod validation;
+pub mod pokemontcg;
 pub mod viewer;
+pub mod config;
 
+// The hashes live in DatasetIndex, packed together with every other entry's.
 pub struct DatasetEntry {
-    pub hash: img_hash::ImageHash,
     pub path: std::path::PathBuf,
+    // Size and modification time (nanoseconds since the epoch) of the image
+    // when it was hashed, to tell which cache entries are stale.
+    pub file_size: u64,
+    pub modified: u64,
+    pub metadata: metadata::CardMetadata,
+    // Index into DatasetIndex::catalogs.
+    pub catalog: usize,
+    // Fingerprint::colors of the image itself.
+    pub colors: Vec<u8>,
+}
+
+// One of several dataset directories that get searched together (e.g.
+// English, Japanese and proxy images), each with its own cache.
+pub struct CatalogSource {
+    pub name: String,
+    pub dataset_path: String,
+    pub cache_filename: String,
+    // Higher priority catalogs win ties.
+    pub priority: i32,
+}
+
+// The dataset/ directory, cached in dataset.bin.
+impl Default for CatalogSource {
+    fn default() -> Self {
+        CatalogSource {
+            name: "default".to_string(),
+            dataset_path: "dataset/".to_string(),
+            cache_filename: "dataset.bin".to_string(),
+            priority: 0,
+        }
+    }
+}
+
+impl CatalogSource {
+    // From name=directory:cache:priority, e.g. proxies=proxies/:proxies.bin:-1.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let invalid = || format!("{}: expected name=directory:cache:priority", spec);
+
+        let (name, rest) = spec.split_once('=').ok_or_else(invalid)?;
+        let mut parts = rest.rsplitn(3, ':');
+        let priority = parts.next().and_then(|p| p.parse().ok()).ok_or_else(invalid)?;
+        let cache_filename = parts.next().filter(|c| !c.is_empty()).ok_or_else(invalid)?;
+        let dataset_path = parts.next().filter(|d| !d.is_empty()).ok_or_else(invalid)?;
+
+        Ok(CatalogSource {
+            name: name.to_string(),
+            dataset_path: dataset_path.to_string(),
+            cache_filename: cache_filename.to_string(),
+            priority,
+        })
+    }
+}
+
+// Catalogs given as name=directory:cache:priority arguments, or the default
+// one if there are none.
+pub fn catalog_sources(specs: &[String]) -> Result<Vec<CatalogSource>, String> {
+    if specs.is_empty() {
+        return Ok(vec![CatalogSource::default()]);
+    }
+
+    specs.iter().map(|spec| CatalogSource::parse(spec)).collect()
+}
+
+// What update_dataset had to do to bring the cache up to date.
+#[derive(Default)]
+pub struct CacheUpdate {
+    pub added: Vec<std::path::PathBuf>,
+    pub changed: Vec<std::path::PathBuf>,
+    pub removed: Vec<std::path::PathBuf>,
+    pub unchanged: usize,
+}
+
+impl CacheUpdate {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.changed.is_empty() && self.removed.is_empty()
+    }
+}
+
+pub struct Candidate<'a> {
+    pub entry: &'a DatasetEntry,
+    pub catalog: &'a index::Catalog,
+    // Combined distance of the whole card hashes.
+    pub distance: u32,
+    // One per HashingConfig::regions entry.
+    pub region_distances: Vec<u32>,
+    // Result of voting across the whole card and its regions, lower is better.
+    pub score: f32,
+    pub evidence: confidence::Evidence,
+    // Probability that this is the right card, see ConfidenceModel.
+    pub confidence: f32,
+}
+
+pub enum Outcome<'a> {
+    // Candidates sorted best first.
+    Match(Vec<Candidate<'a>>),
+    // The best candidate didn't pass ProcessingOptions::rejection. The
+    // candidates are kept for debugging.
+    Unknown(Vec<Candidate<'a>>),
+}
+
+impl<'a> Outcome<'a> {
+    pub fn candidates(&self) -> &[Candidate<'a>] {
+        match self {
+            Outcome::Match(candidates) => candidates,
+            Outcome::Unknown(candidates) => candidates,
+        }
+    }
+
+    pub fn best(&self) -> Option<&Candidate<'a>> {
+        match self {
+            Outcome::Match(candidates) => candidates.first(),
+            Outcome::Unknown(_) => None,
+        }
+    }
+}
+
+pub struct SetMatch<'a> {
+    pub set: &'a str,
+    // How far under the template's threshold the best position was, relative
+    // to the threshold: 1 is a perfect match, 0 is right at the threshold and
+    // negative scores weren't found. Comparable across templates.
+    pub score: f32,
+    // Top left corner of the best position in the warped card.
+    pub location: (u32, u32),
+    // Template scale and angle (degrees) at that position.
+    pub scale: f32,
+    pub angle: f32,
+}
+
+impl<'a> SetMatch<'a> {
+    pub fn found(&self) -> bool {
+        self.score > 0.0
+    }
 }
 
 #[derive(Default)]
@@ -26,12 +173,27 @@ pub struct ProcessingTimes {
     pub hough: std::time::Duration,
     pub corners: std::time::Duration,
     pub perspective: std::time::Duration,
+    pub glare: std::time::Duration,
     pub phash: std::time::Duration,
 }
 
 pub struct ProcessingPipeline<'a> {
     pub frame: &'a [u8],
     pub buffers: &'a mut ProcessingBuffers,
+    pub options: &'a ProcessingOptions,
+}
+
+#[derive(Default)]
+pub struct ProcessingOptions {
+    // Ignore hash cells covered by glare when comparing against the dataset.
+    pub glare_masking: bool,
+    // Which dataset entries can be matched, as returned by
+    // DatasetIndex::allowed. None allows every entry.
+    pub allowed: Option<Vec<bool>>,
+    pub rejection: rejection::Rejection,
+    pub confidence: confidence::ConfidenceModel,
+    // Scales and angles to try set symbol templates at.
+    pub set_search: set_symbol_detection::SearchRange,
 }
 
 pub struct ProcessingBuffers {
@@ -44,6 +206,8 @@ pub struct ProcessingBuffers {
     pub corners: Vec<(f64, f64)>,
     pub source_image: image::DynamicImage,
     pub perspective_image: image::DynamicImage,
+    pub glare: Vec<bool>,
+    pub glare_area: u32,
 }
 
 impl ProcessingBuffers {
@@ -58,6 +222,8 @@ impl ProcessingBuffers {
             corners: vec![],
             source_image: image::DynamicImage::new_rgba8(width, height),
             perspective_image: image::DynamicImage::new_rgba8(0, 0),
+            glare: vec![],
+            glare_area: 0,
         };
 
         b.sobel.resize((width * height) as usize, 0);
@@ -66,6 +232,25 @@ impl ProcessingBuffers {
 
         b
     }
+
+    // Fills the source image and frame from a photo instead of a camera,
+    // scaling it down to fit width x height.
+    pub fn load_photo(&mut self, photo: &image::DynamicImage, width: u32, height: u32, frame: &mut Vec<u8>) {
+        self.source_image = photo.resize(width, height, image::imageops::Nearest);
+
+        // I should be able to pass this luma8 image using traits
+        // instead of having to build a custom frame
+        let luma8 = self.source_image.to_luma8();
+        self.width = luma8.width();
+        self.height = luma8.height();
+
+        frame.resize((self.width * self.height * 2) as usize, 0);
+        for y in 0 .. luma8.height() {
+            for x in 0 .. luma8.width() {
+                frame[(y * luma8.width() + x) as usize * 2] = luma8.get_pixel(x, y)[0];
+            }
+        }
+    }
 }
 
 
@@ -95,79 +280,265 @@ pub fn colors(image: &image::DynamicImage) -> Vec<(u8, u8, u8)> {
     return sorted.iter().map(|&(k, _)| *k).collect();
 }
 
-pub fn calculate_dataset_entry(path: &std::path::Path) -> DatasetEntry {
-    let hasher = img_hash::HasherConfig::new()
-        .hash_size(16,16)
-        .hash_alg(img_hash::HashAlg::Gradient)
-        .to_hasher();
+// None if the file is gone.
+fn file_stamp(path: &std::path::Path) -> Option<(u64, u64)> {
+    let metadata = std::fs::metadata(path).ok()?;
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|m| m.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+
+    Some((metadata.len(), modified))
+}
+
+fn is_image(path: &std::path::Path) -> bool {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some(extension) => ["jpg", "jpeg", "png", "webp"].iter().any(|e| e.eq_ignore_ascii_case(extension)),
+        None => false,
+    }
+}
+
+// Hashes the image and `augmented` perturbed copies of it.
+pub fn calculate_dataset_entry(
+    path: &std::path::Path,
+    config: &hashing::HashingConfig,
+    metadata: &metadata::MetadataSource,
+    augmented: usize,
+) -> (DatasetEntry, Vec<hashing::Fingerprint>) {
+    let (file_size, modified) = file_stamp(path).unwrap_or_default();
 
     let file = std::fs::File::open(path).unwrap();
     let img = image::io::Reader::new(std::io::BufReader::new(file))
         .with_guessed_format()
         .unwrap()
         .decode()
-        .unwrap()
-        .blur(1.5);
+        .unwrap();
+
+    let fingerprints = std::iter::once(config.hash(&img))
+        .chain(augment::variants(&img, augmented).iter().map(|variant| config.hash(variant)))
+        .collect::<Vec<_>>();
+
+    (
+        DatasetEntry {
+            path: path.to_path_buf(),
+            file_size,
+            modified,
+            metadata: metadata.read(path),
+            catalog: 0,
+            colors: fingerprints[0].colors.clone(),
+        },
+        fingerprints,
+    )
+}
 
-    DatasetEntry {
-        hash: hasher.hash_image(&img),
-        path: path.to_path_buf(),
+fn load_cache(dataset_cache_filename: &str, config: &hashing::HashingConfig) -> Option<index::DatasetIndex> {
+    match cache::load(dataset_cache_filename, config) {
+        Ok(dataset) => Some(dataset),
+        Err(cache::CacheError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => {
+            println!("ignoring {}: {}", dataset_cache_filename, e);
+            None
+        },
     }
 }
 
+// Trusts the cache if there's a valid one, without checking it against the
+// dataset directory.
+pub fn load_or_build_dataset(
+    dataset_path: &str,
+    dataset_cache_filename: &str,
+    config: &hashing::HashingConfig,
+    metadata: &metadata::MetadataSource,
+) -> index::DatasetIndex {
+    match load_cache(dataset_cache_filename, config) {
+        Some(dataset) => dataset,
+        None => rebuild_dataset(None, dataset_path, dataset_cache_filename, config, metadata, 0).0,
+    }
+}
 
-pub fn load_or_build_dataset(dataset_path: &str, dataset_cache_filename: &str) -> Vec<DatasetEntry> {
-    if std::path::Path::new(dataset_cache_filename).exists() {
-        std::io::BufReader::new(std::fs::File::open(dataset_cache_filename).unwrap())
-            .lines()
-            .filter_map(Result::ok)
-            .map(|line| {
-                // DatasetEntry.deserialize
-                let parts = line.split(' ').collect::<Vec<_>>();
+// Brings the cache up to date with the dataset directory, only hashing images
+// that are new or whose size or modification time changed. Metadata is cheap,
+// so it's read again for every entry. Each image is also hashed through
+// `augmented` perturbed copies (see augment.rs); changing that number rehashes
+// everything.
+pub fn update_dataset(
+    dataset_path: &str,
+    dataset_cache_filename: &str,
+    config: &hashing::HashingConfig,
+    metadata: &metadata::MetadataSource,
+    augmented: usize,
+) -> (index::DatasetIndex, CacheUpdate) {
+    let previous = load_cache(dataset_cache_filename, config);
+    rebuild_dataset(previous, dataset_path, dataset_cache_filename, config, metadata, augmented)
+}
 
-                DatasetEntry {
-                        path: std::path::PathBuf::from(&parts[0]),
-                        hash: img_hash::ImageHash::from_base64(&parts[1]).unwrap(),
-                }
-            })
-            .collect::<Vec<_>>()
-    } else {
-        let dataset = std::fs::read_dir(dataset_path)
-            .unwrap()
-            .filter_map(Result::ok)
-            .collect::<Vec<_>>()
-            .par_iter()
-            .map(|p| calculate_dataset_entry(&p.path()))
-            .collect::<Vec<_>>();
-
-        let mut file = std::fs::File::create(dataset_cache_filename).unwrap();
-        for entry in dataset.iter() {
-            // DatasetEntry.serialize
-            file.write(
-                format!(
-                    "{} {}\n",
-                    entry.path.to_str().unwrap(),
-                    entry.hash.to_base64(),
-                ).as_bytes(),
-            ).unwrap();
+// Loads every catalog like load_or_build_dataset does and merges them into
+// one index. A single catalog is used as it is, so that its hashes stay in
+// the cache's map.
+pub fn load_catalogs(
+    sources: &[CatalogSource],
+    config: &hashing::HashingConfig,
+    metadata: &metadata::MetadataSource,
+) -> index::DatasetIndex {
+    if let [source] = sources {
+        let mut dataset = load_or_build_dataset(&source.dataset_path, &source.cache_filename, config, metadata);
+        dataset.catalogs = vec![index::Catalog { name: source.name.clone(), priority: source.priority }];
+        return dataset;
+    }
+
+    let catalogs = sources
+        .iter()
+        .map(|source| {
+            let catalog = index::Catalog { name: source.name.clone(), priority: source.priority };
+            let dataset = load_or_build_dataset(&source.dataset_path, &source.cache_filename, config, metadata);
+
+            (catalog, dataset)
+        })
+        .collect();
+
+    index::DatasetIndex::merge(config.clone(), catalogs)
+}
+
+fn rebuild_dataset(
+    previous: Option<index::DatasetIndex>,
+    dataset_path: &str,
+    dataset_cache_filename: &str,
+    config: &hashing::HashingConfig,
+    metadata: &metadata::MetadataSource,
+    augmented: usize,
+) -> (index::DatasetIndex, CacheUpdate) {
+    let mut known = std::collections::HashMap::new();
+    if let Some(previous) = &previous {
+        for (i, entry) in previous.entries.iter().enumerate() {
+            known.insert(entry.path.clone(), i);
+        }
+    }
+
+    let mut update = CacheUpdate::default();
+    let mut dataset = vec![];
+    let mut stale = vec![];
+
+    let paths = std::fs::read_dir(dataset_path)
+        .unwrap()
+        .filter_map(Result::ok)
+        .map(|p| p.path())
+        .filter(|p| is_image(p))
+        .collect::<Vec<_>>();
+
+    for path in paths {
+        // Removed since it was listed. If it was cached, it stays in known and
+        // is reported as removed.
+        let (file_size, modified) = match file_stamp(&path) {
+            Some(stamp) => stamp,
+            None => continue,
+        };
+
+        match (&previous, known.remove(&path)) {
+            // Previous hashes are only reusable if they cover the same variants.
+            (Some(previous), Some(i))
+                if previous.entries[i].file_size == file_size
+                    && previous.entries[i].modified == modified
+                    && previous.variants == augmented + 1 =>
+            {
+                let metadata = metadata.read(&path);
+                let colors = previous.entries[i].colors.clone();
+                let entry = DatasetEntry { path, file_size, modified, metadata, catalog: 0, colors };
+                dataset.push((entry, previous.fingerprints(i)));
+                update.unchanged += 1;
+            },
+            (_, Some(_)) => {
+                update.changed.push(path.clone());
+                stale.push(path);
+            },
+            (_, None) => {
+                update.added.push(path.clone());
+                stale.push(path);
+            },
         }
+    }
+
+    update.removed = known.into_keys().collect();
+    update.removed.sort();
+
+    dataset.extend(
+        stale
+            .par_iter()
+            .map(|path| calculate_dataset_entry(path, config, metadata, augmented))
+            .collect::<Vec<_>>()
+    );
+    dataset.sort_by(|a, b| a.0.path.cmp(&b.0.path));
+
+    let dataset = index::DatasetIndex::new(config.clone(), augmented + 1, dataset);
+    cache::save(dataset_cache_filename, &dataset).unwrap();
+
+    (dataset, update)
+}
+
+pub const HASHING_CONFIG: &str = "hashing.json";
+
+// HASHING_CONFIG, or the default configuration if there's none. Every bin
+// hashes with it, so that the dataset cache and the queries agree (caches
+// hashed with another configuration get rebuilt).
+pub fn load_hashing_config() -> Result<hashing::HashingConfig, config::ConfigError> {
+    match config::read(std::path::Path::new(HASHING_CONFIG))? {
+        Some(json) => hashing::HashingConfig::from_json(&json).map_err(config::ConfigError::Invalid),
+        None => Ok(hashing::HashingConfig::default()),
+    }
+}
+
+pub const REJECTION_FILE: &str = "rejection.json";
+
+// The thresholds calibrate-rejection wrote to REJECTION_FILE for this hashing
+// configuration, or none (everything is accepted).
+pub fn load_rejection(config: &hashing::HashingConfig) -> Result<rejection::Rejection, config::ConfigError> {
+    match config::read_calibrated(std::path::Path::new(REJECTION_FILE), config)? {
+        Some(json) => rejection::Rejection::from_json(&json).map_err(config::ConfigError::Invalid),
+        None => Ok(rejection::Rejection::default()),
+    }
+}
+
+pub fn save_rejection(config: &hashing::HashingConfig, rejection: &rejection::Rejection) -> std::io::Result<()> {
+    config::write_calibrated(std::path::Path::new(REJECTION_FILE), config, rejection.to_json())
+}
+
+pub const CONFIDENCE_FILE: &str = "confidence.json";
 
-        dataset
+// The model calibrate-confidence wrote to CONFIDENCE_FILE for this hashing
+// configuration, or the default guess.
+pub fn load_confidence(config: &hashing::HashingConfig) -> Result<confidence::ConfidenceModel, config::ConfigError> {
+    match config::read_calibrated(std::path::Path::new(CONFIDENCE_FILE), config)? {
+        Some(json) => confidence::ConfidenceModel::from_json(&json).map_err(config::ConfigError::Invalid),
+        None => Ok(confidence::ConfidenceModel::default()),
     }
 }
 
-pub fn load_templates() -> Vec<(&'static str, f32, image::DynamicImage)> {
-    vec![
-        ("cpa",   0.20, image::open("templates/cpa.png").unwrap()),
-        ("ssh",   0.10, image::open("templates/ssh.png").unwrap()),
-        ("exp",   0.10, image::open("templates/exp.png").unwrap()),
-        ("gen",   0.18, image::open("templates/gen.png").unwrap()),
-        ("pls",   0.15, image::open("templates/pls.png").unwrap()),
-        ("lc",    0.10, image::open("templates/lc.png").unwrap()),
-        ("promo", 0.20, image::open("templates/promo.png").unwrap()),
-        ("bst",   0.20, image::open("templates/bst.png").unwrap()),
-        ("sum",   0.10, image::open("templates/sum.png").unwrap()),
-    ]
+pub fn save_confidence(config: &hashing::HashingConfig, model: &confidence::ConfidenceModel) -> std::io::Result<()> {
+    config::write_calibrated(std::path::Path::new(CONFIDENCE_FILE), config, model.to_json())
+}
+
+pub const TEMPLATE_MANIFEST: &str = "templates/manifest.json";
+
+// The templates listed in TEMPLATE_MANIFEST, or if there's no manifest,
+// every SVG next to where it would be (the ones the viewer shows), or none (no
+// set detection). SVGs are sized for the eras of their set's dataset cards
+// unless they say otherwise.
+pub fn load_templates(dataset: &index::DatasetIndex) -> Result<Vec<templates::Template>, templates::TemplateError> {
+    let set_eras = templates::set_eras(dataset.entries.iter().map(|e| &e.metadata));
+
+    let manifest = std::path::Path::new(TEMPLATE_MANIFEST);
+    if manifest.exists() {
+        return templates::load(manifest, &set_eras);
+    }
+
+    let directory = manifest.parent().unwrap_or_else(|| std::path::Path::new(""));
+    let svgs = if directory.is_dir() { templates::load_svgs(directory, &set_eras)? } else { vec![] };
+    if svgs.is_empty() {
+        println!("no {} and no SVG set symbols, set detection disabled", TEMPLATE_MANIFEST);
+    }
+
+    Ok(svgs)
 }
 
 pub trait Luma<T> {
@@ -224,11 +595,14 @@ impl<'a, T> Luma<T> for LumaVec<'a, T> where T: Copy {
     }
 }
 
+// How many of the nearest whole card matches get re-ranked by region voting.
+const SHORTLIST: usize = 32;
+
 pub fn process<'a>(
     processing: &mut ProcessingPipeline,
-    dataset: &'a Vec<DatasetEntry>,
-    templates: &Vec<(&'a str, f32, image::DynamicImage)>,
-) -> (ProcessingTimes, Option<Vec<(&'a DatasetEntry, u32)>>, Option<&'a str>) {
+    dataset: &'a index::DatasetIndex,
+    templates: &'a [templates::Template],
+) -> (ProcessingTimes, Option<Outcome<'a>>, Vec<SetMatch<'a>>) {
     let mut times = ProcessingTimes::default();
 
     let width = processing.buffers.width;
@@ -253,14 +627,16 @@ pub fn process<'a>(
     time = Instant::now();
 
     processing.buffers.perspective_image = image::DynamicImage::new_rgba8(734, 1024);
+    processing.buffers.glare.truncate(0);
+    processing.buffers.glare_area = 0;
     if processing.buffers.corners.is_empty() {
-        return (times, None, None);
+        return (times, None, vec![]);
     }
 
     let buffer = 5;
     let c = perspective::calculate(&processing.buffers.corners, 734.0 + buffer as f64 * 2.0, 1024.0 + buffer as f64 * 2.0);
     if c.is_none() {
-        return (times, None, None);
+        return (times, None, vec![]);
     }
 
     let c = c.unwrap();
@@ -283,50 +659,178 @@ pub fn process<'a>(
     times.perspective = time.elapsed();
     time = Instant::now();
 
-    let hasher = img_hash::HasherConfig::new()
-        .hash_size(16,16)
-        .hash_alg(img_hash::HashAlg::Gradient)
-        .to_hasher();
+    processing.buffers.glare_area = glare::calculate(&processing.buffers.perspective_image, &mut processing.buffers.glare);
 
-    let hash = hasher.hash_image(&processing.buffers.perspective_image);
+    let hashing = &dataset.config;
+    // Only when some cell is actually masked, since the masked search can't
+    // use the index.
+    let glare_cells = if processing.options.glare_masking && processing.buffers.glare_area > 0 {
+        Some(hashing.glare_cells(&processing.buffers.glare, 734, 1024))
+            .filter(|cells| cells.iter().flatten().any(|spec| spec.contains(&true)))
+    } else {
+        None
+    };
 
-    let mut dataset_indexes = (0 .. dataset.len()).collect::<Vec<_>>();
-    dataset_indexes.sort_by_key(|i| hash.dist(&dataset[*i].hash));
+    times.glare = time.elapsed();
+    time = Instant::now();
 
-    let detected_set = detect_set(&processing.buffers.perspective_image.grayscale(), templates);
+    let fingerprint = hashing.hash(&processing.buffers.perspective_image);
+    let card = dataset.card.pack(&fingerprint.card);
+    let regions = dataset.regions.pack(&fingerprint.regions);
 
-    times.phash = time.elapsed();
+    let allowed = processing.options.allowed.as_deref();
 
-    match detected_set {
-        Some(set) => {
-            dataset_indexes[0..3].sort_by_key(|i| if dataset[*i].path.to_str().unwrap().contains(set) { 0 } else { 1 });
+    // Masked distances aren't a metric, so those can't go through the tree.
+    let nearest = match &glare_cells {
+        Some(cells) => {
+            let mask = dataset.card.mask(cells);
+            let rows = dataset.card.nearest_by(SHORTLIST * dataset.variants, allowed, |row| {
+                dataset.card.masked_distance(&card, &mask, row)
+            });
+            dataset.entries_of(rows, SHORTLIST)
         },
-        None => {}
+        None => dataset.nearest(&card, SHORTLIST, allowed),
+    };
+
+    let mut candidates = nearest
+        .into_iter()
+        .map(|(i, distance)| {
+            let region_distances = dataset.segment_distances(&regions, i);
+            let score = hashing.score(&fingerprint, distance, &region_distances);
+
+            let entry = &dataset.entries[i];
+            Candidate {
+                entry,
+                catalog: &dataset.catalogs[entry.catalog],
+                distance,
+                region_distances,
+                score,
+                evidence: confidence::Evidence::default(),
+                confidence: 0.0,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    candidates.sort_by(|a, b| {
+        a.score
+            .partial_cmp(&b.score)
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then(b.catalog.priority.cmp(&a.catalog.priority))
+    });
+    candidates.truncate(3);
+
+    // Only templates for the eras of some candidate, in the layouts of those
+    // eras, or every layout if some candidate's era isn't known.
+    let templates = templates
+        .iter()
+        .filter(|t| candidates.iter().any(|c| t.applies_to(&c.entry.metadata)))
+        .collect::<Vec<_>>();
+    let eras = candidates.iter().map(|c| layout::Era::of(&c.entry.metadata)).collect::<Option<Vec<_>>>();
+    let layouts = match eras {
+        Some(eras) => layout::ERAS.iter().filter(|e| eras.contains(e)).map(|&e| layout::Layout::of(e)).collect(),
+        None => layout::profiles(),
+    };
+
+    let set_matches = detect_sets(
+        &processing.buffers.perspective_image.to_luma8(),
+        &templates,
+        &layouts,
+        &processing.options.set_search,
+    );
+
+    times.phash = time.elapsed();
+
+    let bits = dataset.card.bits().max(1) as f32;
+    let glare = processing.buffers.glare_area as f32 / (734.0 * 1024.0);
+    let sharpness = confidence::sharpness(&processing.buffers.perspective_image);
+    let mut distances = candidates.iter().map(|c| c.distance).collect::<Vec<_>>();
+
+    for (i, candidate) in candidates.iter_mut().enumerate() {
+        let closest_other = distances.iter().enumerate().filter(|&(j, _)| j != i).map(|(_, &d)| d).min();
+        let best_set = |own: bool| {
+            set_matches
+                .iter()
+                .filter(|m| candidate.entry.metadata.in_set(m.set) == own)
+                .map(|m| m.score)
+                .fold(0.0, f32::max)
+        };
+
+        let evidence = confidence::Evidence {
+            distance: candidate.distance as f32 / bits,
+            margin: closest_other.map(|d| (d as f32 - candidate.distance as f32) / bits).unwrap_or(0.0),
+            set: best_set(true),
+            other_set: best_set(false),
+            color: confidence::color_similarity(&fingerprint.colors, &candidate.entry.colors),
+            glare,
+            sharpness,
+        };
+
+        candidate.confidence = processing.options.confidence.confidence(&evidence);
+        candidate.evidence = evidence;
     }
 
-    (
-        times,
-        Some(vec![
-            (&dataset[dataset_indexes[0]], hash.dist(&dataset[dataset_indexes[0]].hash)),
-            (&dataset[dataset_indexes[1]], hash.dist(&dataset[dataset_indexes[1]].hash)),
-            (&dataset[dataset_indexes[2]], hash.dist(&dataset[dataset_indexes[2]].hash)),
-        ]),
-        detected_set,
-    )
+    // Rejection thresholds are about hash distances, so they're checked on
+    // the nearest candidate and its gap to the second nearest, whatever the
+    // confidence model thinks.
+    distances.sort_unstable();
+    let accepted = match distances.first() {
+        Some(&nearest) => {
+            let gap = distances.get(1).map(|second| second.saturating_sub(nearest));
+            processing.options.rejection.accepts(nearest, gap)
+        },
+        None => false,
+    };
+
+    // Candidates from the best scoring set whose symbol was found come first,
+    // then the most confident.
+    let found_set = set_matches.iter().find(|m| m.found()).map(|m| m.set);
+    let in_found_set = |c: &Candidate| found_set.map(|set| c.entry.metadata.in_set(set)).unwrap_or(false);
+
+    candidates.sort_by(|a, b| {
+        in_found_set(b)
+            .cmp(&in_found_set(a))
+            .then(b.confidence.partial_cmp(&a.confidence).unwrap_or(std::cmp::Ordering::Equal))
+            .then(b.catalog.priority.cmp(&a.catalog.priority))
+    });
+
+    let outcome = if accepted {
+        Outcome::Match(candidates)
+    } else {
+        Outcome::Unknown(candidates)
+    };
+
+    (times, Some(outcome), set_matches)
 }
 
-pub fn detect_set<'a>(image: &image::DynamicImage, templates: &Vec<(&'a str, f32, image::DynamicImage)>) -> Option<&'a str> {
-    templates
+// Every template, best scoring first, searched in the set symbol boxes of the
+// given layouts unless it has its own areas, over the range its threshold was
+// calibrated for, or the given one if it wasn't.
+pub fn detect_sets<'a>(
+    image: &image::GrayImage,
+    templates: &[&'a templates::Template],
+    layouts: &[layout::Layout],
+    range: &set_symbol_detection::SearchRange,
+) -> Vec<SetMatch<'a>> {
+    let layouts = layouts.iter().collect::<Vec<_>>();
+    let (width, height) = image.dimensions();
+
+    let mut matches = templates
         .par_iter()
         .map(|t| {
-            let (score, _) = set_symbol_detection::detect(&image, &t.2, t.1);
-
-            if score != 1.0 {
-                Some(t.0)
-            } else {
-                None
+            let areas = t.search_areas(&layouts, width, height);
+            let range = t.search.as_ref().unwrap_or(range);
+            let detection = set_symbol_detection::detect(image, &t.image, t.threshold, &areas, range, false);
+
+            SetMatch {
+                set: t.code.as_str(),
+                score: 1.0 - detection.score / t.threshold,
+                location: detection.location,
+                scale: detection.scale,
+                angle: detection.angle,
             }
         })
-        .find_first(Option::is_some)
-        .flatten()
+        .collect::<Vec<_>>();
+
+    matches.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal));
+    matches
 }
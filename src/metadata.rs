@@ -0,0 +1,224 @@
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CardMetadata {
+    // Set code, as used by the set symbol templates (e.g. "ssh").
+    pub set: Option<String>,
+    pub number: Option<String>,
+    pub name: Option<String>,
+    pub rarity: Option<String>,
+    pub supertype: Option<String>,
+    pub regulation_mark: Option<String>,
+    pub series: Option<String>,
+    // As YYYY/MM/DD, so that dates compare as strings.
+    pub release_date: Option<String>,
+}
+
+impl CardMetadata {
+    pub fn fields(&self) -> [(&'static str, &Option<String>); 8] {
+        [
+            ("set", &self.set),
+            ("number", &self.number),
+            ("name", &self.name),
+            ("rarity", &self.rarity),
+            ("supertype", &self.supertype),
+            ("regulation_mark", &self.regulation_mark),
+            ("series", &self.series),
+            ("release_date", &self.release_date),
+        ]
+    }
+
+    pub fn assign(&mut self, field: &str, value: &str) -> bool {
+        let value = Some(value.trim().to_string());
+
+        match field.trim() {
+            "set" => self.set = value,
+            "number" => self.number = value,
+            "name" => self.name = value,
+            "rarity" => self.rarity = value,
+            "supertype" => self.supertype = value,
+            "regulation_mark" => self.regulation_mark = value,
+            "series" => self.series = value,
+            "release_date" => self.release_date = value,
+            _ => return false,
+        }
+
+        true
+    }
+
+    // Overwrites fields with every value that the other one has.
+    pub fn merge(&mut self, other: &CardMetadata) {
+        for (field, value) in other.fields().iter() {
+            if let Some(value) = value {
+                self.assign(field, value);
+            }
+        }
+    }
+
+    pub fn in_set(&self, set: &str) -> bool {
+        self.set.as_ref().map(|s| s.eq_ignore_ascii_case(set)).unwrap_or(false)
+    }
+}
+
+// Where dataset entries get their metadata from. Sidecar values take
+// precedence over catalog values, which take precedence over values parsed
+// from the filename.
+pub struct MetadataSource {
+    // Matched against the image's file stem, with fields in braces, e.g.
+    // "{set}-{number}" for "ssh-123.jpg".
+    pub filename_pattern: Option<String>,
+    // Metadata by card ID, which is matched against the image's file stem.
+    pub catalog: Option<std::collections::HashMap<String, CardMetadata>>,
+    // A text file next to the image, with the same stem and a .txt extension,
+    // holding one "field=value" per line.
+    pub sidecar: bool,
+}
+
+impl Default for MetadataSource {
+    fn default() -> Self {
+        MetadataSource {
+            filename_pattern: Some("{set}-{number}".to_string()),
+            catalog: None,
+            sidecar: true,
+        }
+    }
+}
+
+impl MetadataSource {
+    pub fn read(&self, path: &std::path::Path) -> CardMetadata {
+        let mut metadata = CardMetadata::default();
+
+        let stem = path.file_stem().and_then(|s| s.to_str());
+
+        if let (Some(pattern), Some(stem)) = (&self.filename_pattern, stem) {
+            for (field, value) in match_pattern(pattern, stem).unwrap_or_default() {
+                metadata.assign(&field, &value);
+            }
+        }
+
+        if let (Some(catalog), Some(stem)) = (&self.catalog, stem) {
+            if let Some(card) = catalog.get(stem) {
+                metadata.merge(card);
+            }
+        }
+
+        if self.sidecar {
+            if let Ok(contents) = std::fs::read_to_string(path.with_extension("txt")) {
+                for line in contents.lines() {
+                    if let Some(separator) = line.find('=') {
+                        metadata.assign(&line[..separator], &line[separator + 1..]);
+                    }
+                }
+            }
+        }
+
+        metadata
+    }
+}
+
+enum Token {
+    Literal(String),
+    Field(String),
+}
+
+fn tokenize(pattern: &str) -> Vec<Token> {
+    let mut tokens = vec![];
+    let mut rest = pattern;
+
+    while !rest.is_empty() {
+        match (rest.find('{'), rest.find('}')) {
+            (Some(0), Some(end)) => {
+                tokens.push(Token::Field(rest[1..end].to_string()));
+                rest = &rest[end + 1..];
+            },
+            (Some(start), _) if start > 0 => {
+                tokens.push(Token::Literal(rest[..start].to_string()));
+                rest = &rest[start..];
+            },
+            _ => {
+                tokens.push(Token::Literal(rest.to_string()));
+                rest = "";
+            },
+        }
+    }
+
+    tokens
+}
+
+// Matches a pattern like "{set}-{number}" against a string, returning the
+// value of every field. Fields are never empty.
+pub fn match_pattern(pattern: &str, value: &str) -> Option<Vec<(String, String)>> {
+    let tokens = tokenize(pattern);
+    let mut fields = vec![];
+
+    if match_tokens(&tokens, value, &mut fields) {
+        Some(fields)
+    } else {
+        None
+    }
+}
+
+fn match_tokens(tokens: &[Token], value: &str, fields: &mut Vec<(String, String)>) -> bool {
+    match tokens.first() {
+        None => value.is_empty(),
+        Some(Token::Literal(literal)) => {
+            value.starts_with(literal.as_str()) && match_tokens(&tokens[1..], &value[literal.len()..], fields)
+        },
+        Some(Token::Field(_)) if value.is_empty() => false,
+        Some(Token::Field(field)) => {
+            // Try the shortest value first, backtracking if the rest doesn't match.
+            for (end, _) in value.char_indices().skip(1).chain(std::iter::once((value.len(), ' '))) {
+                fields.push((field.clone(), value[..end].to_string()));
+                if match_tokens(&tokens[1..], &value[end..], fields) {
+                    return true;
+                }
+                fields.pop();
+            }
+
+            false
+        },
+    }
+}
+
+// Restricts which dataset entries a frame can be matched against. Empty lists
+// and missing bounds allow anything; entries missing a field that is filtered
+// on are left out.
+#[derive(Clone, Debug, Default)]
+pub struct CardFilter {
+    pub sets: Vec<String>,
+    // Inclusive bounds, as YYYY/MM/DD.
+    pub released_from: Option<String>,
+    pub released_until: Option<String>,
+    pub regulation_marks: Vec<String>,
+    pub supertypes: Vec<String>,
+}
+
+impl CardFilter {
+    pub fn is_empty(&self) -> bool {
+        self.sets.is_empty()
+            && self.released_from.is_none()
+            && self.released_until.is_none()
+            && self.regulation_marks.is_empty()
+            && self.supertypes.is_empty()
+    }
+
+    pub fn matches(&self, metadata: &CardMetadata) -> bool {
+        let released = |bound: &Option<String>, allowed: fn(&str, &str) -> bool| match (bound, &metadata.release_date) {
+            (None, _) => true,
+            (Some(bound), Some(date)) => allowed(date, bound),
+            (Some(_), None) => false,
+        };
+
+        any_of(&self.sets, &metadata.set)
+            && any_of(&self.regulation_marks, &metadata.regulation_mark)
+            && any_of(&self.supertypes, &metadata.supertype)
+            && released(&self.released_from, |date, bound| date >= bound)
+            && released(&self.released_until, |date, bound| date <= bound)
+    }
+}
+
+fn any_of(allowed: &[String], value: &Option<String>) -> bool {
+    match value {
+        _ if allowed.is_empty() => true,
+        Some(value) => allowed.iter().any(|a| a.eq_ignore_ascii_case(value)),
+        None => false,
+    }
+}
@@ -0,0 +1,195 @@
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Equalization {
+    None,
+    Global,
+    Clahe,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Normalization {
+    pub white_balance: bool,
+    pub equalization: Equalization,
+}
+
+impl Default for Normalization {
+    fn default() -> Self {
+        Normalization {
+            white_balance: false,
+            equalization: Equalization::None,
+        }
+    }
+}
+
+impl Normalization {
+    // From { "white_balance": true, "equalization": "clahe" }, where
+    // equalization is none, global or clahe and missing fields are off.
+    pub fn from_json(json: &serde_json::Value) -> Result<Self, String> {
+        let white_balance = match json.get("white_balance") {
+            None => false,
+            Some(white_balance) => white_balance.as_bool().ok_or("white_balance must be true or false")?,
+        };
+
+        let equalization = match json.get("equalization").map(|e| e.as_str()) {
+            None | Some(Some("none")) => Equalization::None,
+            Some(Some("global")) => Equalization::Global,
+            Some(Some("clahe")) => Equalization::Clahe,
+            Some(_) => return Err("equalization must be none, global or clahe".to_string()),
+        };
+
+        Ok(Normalization { white_balance, equalization })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.white_balance || self.equalization != Equalization::None
+    }
+
+    pub fn name(&self) -> String {
+        let mut steps = vec![];
+        if self.white_balance {
+            steps.push("gray-world");
+        }
+        match self.equalization {
+            Equalization::None => {},
+            Equalization::Global => steps.push("equalize"),
+            Equalization::Clahe => steps.push("clahe"),
+        }
+
+        if steps.is_empty() {
+            "none".to_string()
+        } else {
+            steps.join("+")
+        }
+    }
+}
+
+pub fn apply(image: &image::DynamicImage, normalization: &Normalization) -> image::DynamicImage {
+    if !normalization.is_enabled() {
+        return image.clone();
+    }
+
+    let mut rgb = image.to_rgb8();
+
+    if normalization.white_balance {
+        gray_world(&mut rgb);
+    }
+
+    match normalization.equalization {
+        Equalization::None => {},
+        Equalization::Global => equalize(&mut rgb, 1, 1, None),
+        Equalization::Clahe => equalize(&mut rgb, 8, 8, Some(2.0)),
+    }
+
+    image::DynamicImage::ImageRgb8(rgb)
+}
+
+// Scale each channel so that the average colour of the image becomes gray.
+fn gray_world(image: &mut image::RgbImage) {
+    let mut sums = [0u64; 3];
+    for p in image.pixels() {
+        for c in 0..3 {
+            sums[c] += p[c] as u64;
+        }
+    }
+
+    let mean = (sums[0] + sums[1] + sums[2]) as f32 / 3.0;
+    let mut gains = [1f32; 3];
+    for c in 0..3 {
+        if sums[c] > 0 {
+            gains[c] = mean / sums[c] as f32;
+        }
+    }
+
+    for p in image.pixels_mut() {
+        for c in 0..3 {
+            p[c] = (p[c] as f32 * gains[c]).min(255.0) as u8;
+        }
+    }
+}
+
+// Histogram equalization of the luma channel over a grid of tiles, with
+// bilinear interpolation between tile mappings. A single tile without a clip
+// limit is plain global equalization; more tiles with a clip limit is CLAHE.
+// Colour is preserved by scaling each pixel's RGB by the luma gain.
+fn equalize(image: &mut image::RgbImage, tiles_x: u32, tiles_y: u32, clip_limit: Option<f32>) {
+    let (width, height) = image.dimensions();
+    if width == 0 || height == 0 {
+        return;
+    }
+
+    let luma = image
+        .pixels()
+        .map(|p| ((299 * p[0] as u32 + 587 * p[1] as u32 + 114 * p[2] as u32) / 1000) as u8)
+        .collect::<Vec<_>>();
+
+    let mut mappings = vec![[0u8; 256]; (tiles_x * tiles_y) as usize];
+    for ty in 0..tiles_y {
+        for tx in 0..tiles_x {
+            let x0 = tx * width / tiles_x;
+            let x1 = (tx + 1) * width / tiles_x;
+            let y0 = ty * height / tiles_y;
+            let y1 = (ty + 1) * height / tiles_y;
+
+            let mut histogram = [0u32; 256];
+            for y in y0..y1 {
+                for x in x0..x1 {
+                    histogram[luma[(y * width + x) as usize] as usize] += 1;
+                }
+            }
+
+            if let Some(limit) = clip_limit {
+                let limit = ((limit * ((x1 - x0) * (y1 - y0)) as f32 / 256.0) as u32).max(1);
+                let mut excess = 0;
+                for h in histogram.iter_mut() {
+                    if *h > limit {
+                        excess += *h - limit;
+                        *h = limit;
+                    }
+                }
+                for h in histogram.iter_mut() {
+                    *h += excess / 256;
+                }
+            }
+
+            let total: u32 = histogram.iter().sum();
+            let mapping = &mut mappings[(ty * tiles_x + tx) as usize];
+            let mut cdf = 0u32;
+            for v in 0..256 {
+                cdf += histogram[v];
+                mapping[v] = if total > 0 {
+                    (cdf as u64 * 255 / total as u64) as u8
+                } else {
+                    v as u8
+                };
+            }
+        }
+    }
+
+    for y in 0..height {
+        for x in 0..width {
+            let fx = ((x as f32 + 0.5) * tiles_x as f32 / width as f32 - 0.5).max(0.0).min((tiles_x - 1) as f32);
+            let fy = ((y as f32 + 0.5) * tiles_y as f32 / height as f32 - 0.5).max(0.0).min((tiles_y - 1) as f32);
+            let tx0 = fx.floor() as u32;
+            let ty0 = fy.floor() as u32;
+            let tx1 = (tx0 + 1).min(tiles_x - 1);
+            let ty1 = (ty0 + 1).min(tiles_y - 1);
+            let ax = fx - tx0 as f32;
+            let ay = fy - ty0 as f32;
+
+            let v = luma[(y * width + x) as usize];
+            let m = |tx: u32, ty: u32| mappings[(ty * tiles_x + tx) as usize][v as usize] as f32;
+            let mapped =
+                (1.0 - ay) * ((1.0 - ax) * m(tx0, ty0) + ax * m(tx1, ty0)) +
+                ay * ((1.0 - ax) * m(tx0, ty1) + ax * m(tx1, ty1));
+
+            let p = image.get_pixel_mut(x, y);
+            if v == 0 {
+                p.0 = [mapped as u8; 3];
+            } else {
+                let gain = mapped / v as f32;
+                for c in 0..3 {
+                    p[c] = (p[c] as f32 * gain).min(255.0) as u8;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,311 @@
+use rayon::prelude::*;
+
+#[derive(Clone, Copy, Debug)]
+struct Segment {
+    offset: usize,
+    words: usize,
+    bytes: usize,
+    weight: u32,
+}
+
+// Several hashes per row, stored back to back as u64 words so that comparing
+// a query against every row is a tight xor + popcount loop with no pointer
+// chasing (a popcnt instruction on x86-64, see .cargo/config.toml). Hash bit
+// i lives in word i / 64, bit i % 64 of its segment.
+pub struct PackedHashes {
+    weights: Vec<u32>,
+    segments: Vec<Segment>,
+    stride: usize,
+    rows: usize,
+    data: Words,
+}
+
+// Where the words live: in memory, or straight in a mapped cache file until
+// something modifies them.
+enum Words {
+    Owned(Vec<u64>),
+    Mapped { map: std::sync::Arc<memmap2::Mmap>, offset: usize, words: usize },
+}
+
+impl Words {
+    fn get(&self) -> &[u64] {
+        match self {
+            Words::Owned(words) => words,
+            // SAFETY: from_mapped checked that these bytes are within the map
+            // and 8 byte aligned, any bit pattern is a valid u64, and the map
+            // lives as long as self.
+            Words::Mapped { map, offset, words } => unsafe {
+                std::slice::from_raw_parts(map.as_ptr().add(*offset) as *const u64, *words)
+            },
+        }
+    }
+
+    fn owned(&mut self) -> &mut Vec<u64> {
+        if let Words::Mapped { .. } = self {
+            *self = Words::Owned(self.get().to_vec());
+        }
+
+        match self {
+            Words::Owned(words) => words,
+            Words::Mapped { .. } => unreachable!(),
+        }
+    }
+}
+
+// Which bits of each segment take part in a masked comparison.
+pub struct Mask {
+    keep: Vec<u64>,
+    kept: Vec<u32>,
+}
+
+impl PackedHashes {
+    // An empty table, with one weight and size (as returned by
+    // HashingConfig::card_segments) per hash in a row.
+    pub fn new(weights: Vec<u32>, segment_bytes: &[usize]) -> Self {
+        let mut packed = PackedHashes { weights, segments: vec![], stride: 0, rows: 0, data: Words::Owned(vec![]) };
+        for (i, &bytes) in segment_bytes.iter().enumerate() {
+            let words = bytes.div_ceil(8);
+            let weight = packed.weights.get(i).copied().unwrap_or(1);
+
+            packed.segments.push(Segment { offset: packed.stride, words, bytes, weight });
+            packed.stride += words;
+        }
+
+        packed
+    }
+
+    // A table over the little endian words written by cache::save, starting
+    // at the given offset of the map. Only copies them on big endian targets.
+    pub fn from_mapped(
+        weights: Vec<u32>,
+        segment_bytes: &[usize],
+        rows: usize,
+        map: std::sync::Arc<memmap2::Mmap>,
+        offset: usize,
+    ) -> Option<Self> {
+        let mut packed = PackedHashes::new(weights, segment_bytes);
+        let words = rows.checked_mul(packed.stride)?;
+        let end = words.checked_mul(8)?.checked_add(offset)?;
+        if end > map.len() || (map.as_ptr() as usize + offset) % std::mem::align_of::<u64>() != 0 {
+            return None;
+        }
+
+        packed.rows = rows;
+        packed.data = if cfg!(target_endian = "little") {
+            Words::Mapped { map, offset, words }
+        } else {
+            Words::Owned(
+                map[offset..end]
+                    .chunks_exact(8)
+                    .map(|w| u64::from_le_bytes([w[0], w[1], w[2], w[3], w[4], w[5], w[6], w[7]]))
+                    .collect(),
+            )
+        };
+
+        Some(packed)
+    }
+
+    pub fn segment_bytes(&self) -> Vec<usize> {
+        self.segments.iter().map(|s| s.bytes).collect()
+    }
+
+    pub fn data(&self) -> &[u64] {
+        self.data.get()
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows == 0
+    }
+
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    pub fn row(&self, i: usize) -> &[u64] {
+        &self.data.get()[i * self.stride .. (i + 1) * self.stride]
+    }
+
+    pub fn push(&mut self, hashes: &[img_hash::ImageHash]) {
+        debug_assert!(
+            hashes.iter().map(|h| h.as_bytes().len()).eq(self.segments.iter().map(|s| s.bytes)),
+            "hash sizes don't match the table's",
+        );
+
+        let row = self.pack(hashes);
+        self.data.owned().extend_from_slice(&row);
+        self.rows += 1;
+    }
+
+    // Appends the given rows of another table, in order. Returns false,
+    // leaving this one untouched, if their hash sizes differ.
+    pub fn extend(&mut self, other: &PackedHashes, rows: &[usize]) -> bool {
+        if rows.is_empty() {
+            return true;
+        }
+
+        if self.segment_bytes() != other.segment_bytes() {
+            return false;
+        }
+
+        let data = self.data.owned();
+        for &row in rows.iter() {
+            data.extend_from_slice(other.row(row));
+        }
+        self.rows += rows.len();
+        true
+    }
+
+    pub fn pack(&self, hashes: &[img_hash::ImageHash]) -> Vec<u64> {
+        let mut row = vec![0u64; self.stride];
+        for (segment, hash) in self.segments.iter().zip(hashes.iter()) {
+            for (j, byte) in hash.as_bytes().iter().take(segment.bytes).enumerate() {
+                row[segment.offset + j / 8] |= (*byte as u64) << (8 * (j % 8));
+            }
+        }
+
+        row
+    }
+
+    pub fn unpack(&self, i: usize) -> Vec<img_hash::ImageHash> {
+        let row = self.row(i);
+
+        self.segments
+            .iter()
+            .map(|segment| {
+                let bytes = (0..segment.bytes)
+                    .map(|j| (row[segment.offset + j / 8] >> (8 * (j % 8))) as u8)
+                    .collect::<Vec<_>>();
+
+                img_hash::ImageHash::from_bytes(&bytes).unwrap()
+            })
+            .collect()
+    }
+
+    // Maximum possible weighted distance.
+    pub fn bits(&self) -> u32 {
+        self.segments.iter().map(|s| s.weight * s.bytes as u32 * 8).sum()
+    }
+
+    pub fn segment_bits(&self) -> Vec<u32> {
+        self.segments.iter().map(|s| s.bytes as u32 * 8).collect()
+    }
+
+    pub fn distance(&self, query: &[u64], i: usize) -> u32 {
+        let row = self.row(i);
+
+        self.segments
+            .iter()
+            .map(|s| {
+                let mut d = 0;
+                for w in s.offset .. s.offset + s.words {
+                    d += (query[w] ^ row[w]).count_ones();
+                }
+                s.weight * d
+            })
+            .sum()
+    }
+
+    pub fn segment_distances(&self, query: &[u64], i: usize) -> Vec<u32> {
+        let row = self.row(i);
+
+        self.segments
+            .iter()
+            .map(|s| (s.offset .. s.offset + s.words).map(|w| (query[w] ^ row[w]).count_ones()).sum::<u32>())
+            .collect()
+    }
+
+    // Builds a mask from per segment cells, as returned by
+    // HashingConfig::glare_cells. Masked cells are left out of comparisons;
+    // segments without cells are compared in full.
+    pub fn mask(&self, cells: &[Option<Vec<bool>>]) -> Mask {
+        let mut keep = vec![0u64; self.stride];
+        let mut kept = vec![];
+
+        for (i, s) in self.segments.iter().enumerate() {
+            let bits = s.bytes * 8;
+            let mut count = 0;
+            for bit in 0..bits {
+                let masked = match cells.get(i) {
+                    Some(Some(cells)) => cells.get(bit).copied().unwrap_or(false),
+                    _ => false,
+                };
+
+                if !masked {
+                    keep[s.offset + bit / 64] |= 1u64 << (bit % 64);
+                    count += 1;
+                }
+            }
+            kept.push(count);
+        }
+
+        Mask { keep, kept }
+    }
+
+    // Hamming distance ignoring masked bits, scaled back to the full hash
+    // size so that it stays comparable with unmasked distances.
+    pub fn masked_distance(&self, query: &[u64], mask: &Mask, i: usize) -> u32 {
+        let row = self.row(i);
+
+        self.segments
+            .iter()
+            .zip(mask.kept.iter())
+            .map(|(s, &kept)| {
+                let bits = s.bytes as u32 * 8;
+                let masked: u32 = (s.offset .. s.offset + s.words)
+                    .map(|w| ((query[w] ^ row[w]) & mask.keep[w]).count_ones())
+                    .sum();
+                // Fully masked hashes can't tell anything apart, so they're
+                // compared unmasked.
+                let d = (masked * bits).checked_div(kept).unwrap_or_else(|| {
+                    (s.offset .. s.offset + s.words).map(|w| (query[w] ^ row[w]).count_ones()).sum()
+                });
+
+                s.weight * d
+            })
+            .sum()
+    }
+
+    // The k rows closest to the query, as (row, distance) sorted by distance.
+    // When given, rows whose allowed flag is false are skipped.
+    pub fn nearest(&self, query: &[u64], k: usize, allowed: Option<&[bool]>) -> Vec<(usize, u32)> {
+        self.nearest_by(k, allowed, |i| self.distance(query, i))
+    }
+
+    // Brute force scan over every allowed row, split across threads.
+    pub fn nearest_by<F>(&self, k: usize, allowed: Option<&[bool]>, distance: F) -> Vec<(usize, u32)>
+    where
+        F: Fn(usize) -> u32 + Sync,
+    {
+        (0..self.rows)
+            .into_par_iter()
+            .filter(|&i| allowed.map(|a| a[i]).unwrap_or(true))
+            .fold(Vec::new, |mut best, i| {
+                insert_nearest(&mut best, k, (distance(i), i));
+                best
+            })
+            .reduce(Vec::new, |mut a, b| {
+                for found in b {
+                    insert_nearest(&mut a, k, found);
+                }
+                a
+            })
+            .into_iter()
+            .map(|(d, i)| (i, d))
+            .collect()
+    }
+}
+
+// Keeps the k smallest (distance, row) pairs, sorted.
+fn insert_nearest(best: &mut Vec<(u32, usize)>, k: usize, found: (u32, usize)) {
+    if best.len() == k && best.last().map(|last| found >= *last).unwrap_or(true) {
+        return;
+    }
+
+    let position = best.binary_search(&found).unwrap_or_else(|p| p);
+    best.insert(position, found);
+    best.truncate(k);
+}
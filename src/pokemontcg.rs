@@ -0,0 +1,108 @@
+// Reads a local copy of the pokemontcg.io data (github.com/PokemonTCG/pokemon-tcg-data):
+//
+//   sets/*.json      arrays of sets
+//   cards/en/*.json  arrays of cards, one file per set, named after the set ID
+//
+// Dataset images are joined with cards by ID, which must be the image's file
+// stem (e.g. "swsh1-1.jpg").
+use crate::index;
+use crate::metadata::CardMetadata;
+
+pub struct JoinReport {
+    pub images_without_metadata: Vec<std::path::PathBuf>,
+    pub metadata_without_images: Vec<String>,
+}
+
+fn invalid(path: &std::path::Path, message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{:?}: {}", path, message))
+}
+
+// Every element of the top level array of every JSON file in the directory,
+// together with the stem of the file it came from.
+fn read_arrays(directory: &std::path::Path) -> std::io::Result<Vec<(String, serde_json::Value)>> {
+    let mut paths = std::fs::read_dir(directory)?
+        .filter_map(Result::ok)
+        .map(|e| e.path())
+        .filter(|p| p.extension().and_then(|e| e.to_str()) == Some("json"))
+        .collect::<Vec<_>>();
+    paths.sort();
+
+    let mut values = vec![];
+    for path in paths {
+        let file = std::io::BufReader::new(std::fs::File::open(&path)?);
+        let json: serde_json::Value = serde_json::from_reader(file).map_err(|e| invalid(&path, &e.to_string()))?;
+        let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or_default().to_string();
+
+        match json {
+            serde_json::Value::Array(elements) => values.extend(elements.into_iter().map(|e| (stem.clone(), e))),
+            _ => return Err(invalid(&path, "expected an array")),
+        }
+    }
+
+    Ok(values)
+}
+
+fn string(value: &serde_json::Value, key: &str) -> Option<String> {
+    value.get(key).and_then(|v| v.as_str()).map(|s| s.to_string())
+}
+
+// Metadata for every card in the data dump, by card ID.
+pub fn load(data_path: &std::path::Path) -> std::io::Result<std::collections::HashMap<String, CardMetadata>> {
+    let mut sets = std::collections::HashMap::new();
+    for (_, set) in read_arrays(&data_path.join("sets"))? {
+        if let Some(id) = string(&set, "id") {
+            sets.insert(id, set);
+        }
+    }
+
+    let mut cards = std::collections::HashMap::new();
+    for (set_id, card) in read_arrays(&data_path.join("cards").join("en"))? {
+        let id = match string(&card, "id") {
+            Some(id) => id,
+            None => continue,
+        };
+
+        let set = sets.get(&set_id);
+
+        // Set symbol templates use the PTCGO code, which not every set has.
+        let set_code = set
+            .and_then(|s| string(s, "ptcgoCode"))
+            .unwrap_or_else(|| set_id.clone())
+            .to_lowercase();
+
+        cards.insert(id, CardMetadata {
+            set: Some(set_code),
+            number: string(&card, "number"),
+            name: string(&card, "name"),
+            rarity: string(&card, "rarity"),
+            supertype: string(&card, "supertype"),
+            regulation_mark: string(&card, "regulationMark"),
+            series: set.and_then(|s| string(s, "series")),
+            release_date: set.and_then(|s| string(s, "releaseDate")),
+        });
+    }
+
+    Ok(cards)
+}
+
+pub fn join_report(catalog: &std::collections::HashMap<String, CardMetadata>, dataset: &index::DatasetIndex) -> JoinReport {
+    let mut images = std::collections::HashSet::new();
+    let mut images_without_metadata = vec![];
+
+    for entry in dataset.entries.iter() {
+        let stem = entry.path.file_stem().and_then(|s| s.to_str()).unwrap_or_default().to_string();
+        if !catalog.contains_key(&stem) {
+            images_without_metadata.push(entry.path.clone());
+        }
+        images.insert(stem);
+    }
+
+    let mut metadata_without_images = catalog
+        .keys()
+        .filter(|id| !images.contains(*id))
+        .cloned()
+        .collect::<Vec<_>>();
+    metadata_without_images.sort();
+
+    JoinReport { images_without_metadata, metadata_without_images }
+}
@@ -0,0 +1,50 @@
+// A part of the card that gets its own hash, in normalized card coordinates
+// (0.0 - 1.0 over the full card, borders included).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Region {
+    pub name: String,
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+    pub weight: f32,
+}
+
+impl Region {
+    pub fn new(name: &str, x: f64, y: f64, width: f64, height: f64, weight: f32) -> Self {
+        Region { name: name.to_string(), x, y, width, height, weight }
+    }
+
+    pub fn header(&self) -> String {
+        format!("{}@{:.3},{:.3},{:.3},{:.3}", self.name, self.x, self.y, self.width, self.height)
+    }
+}
+
+// These suit modern (BW onwards) layouts reasonably well.
+pub fn default_regions() -> Vec<Region> {
+    vec![
+        Region::new("name",    0.06, 0.03, 0.88, 0.07, 1.0),
+        Region::new("artwork", 0.08, 0.10, 0.84, 0.40, 2.0),
+        Region::new("attacks", 0.06, 0.52, 0.88, 0.33, 1.0),
+        Region::new("bottom",  0.06, 0.86, 0.88, 0.10, 0.5),
+    ]
+}
+
+// Combines normalized distances (0.0 is identical, 1.0 is every bit
+// different) with their weights, leaving out the worst one when there are at
+// least three, so that a single occluded region can't veto a match.
+pub fn vote(distances: &[(f32, f32)]) -> f32 {
+    let mut sorted = distances.to_vec();
+    sorted.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+
+    if sorted.len() >= 3 {
+        sorted.pop();
+    }
+
+    let total_weight: f32 = sorted.iter().map(|(_, w)| w).sum();
+    if total_weight <= 0.0 {
+        return 1.0;
+    }
+
+    sorted.iter().map(|(d, w)| d * w).sum::<f32>() / total_weight
+}
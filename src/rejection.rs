@@ -0,0 +1,88 @@
+// Open-set rejection: whether the best candidate is close enough, and far
+// enough ahead of the second best, to be trusted. Distances are whole card
+// distances, so thresholds only make sense for the HashingConfig they were
+// calibrated with. None disables a check.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Rejection {
+    pub max_distance: Option<u32>,
+    pub min_gap: Option<u32>,
+}
+
+impl Rejection {
+    // From { "max_distance": 40, "min_gap": 3 }, where a missing or null
+    // threshold disables its check.
+    pub fn from_json(json: &serde_json::Value) -> Result<Self, String> {
+        let threshold = |name: &str| match json.get(name) {
+            None | Some(serde_json::Value::Null) => Ok(None),
+            Some(value) => value
+                .as_u64()
+                .filter(|&t| t <= u32::MAX as u64)
+                .map(|t| Some(t as u32))
+                .ok_or_else(|| format!("{} must be a distance", name)),
+        };
+
+        Ok(Rejection { max_distance: threshold("max_distance")?, min_gap: threshold("min_gap")? })
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({ "max_distance": self.max_distance, "min_gap": self.min_gap })
+    }
+
+    // The gap is None when there's no second candidate.
+    pub fn accepts(&self, distance: u32, gap: Option<u32>) -> bool {
+        let close = self.max_distance.map(|max| distance <= max).unwrap_or(true);
+        let ahead = match (self.min_gap, gap) {
+            (Some(min), Some(gap)) => gap >= min,
+            _ => true,
+        };
+
+        close && ahead
+    }
+}
+
+// The best candidate for a labeled photo.
+pub struct Sample {
+    pub distance: u32,
+    pub gap: Option<u32>,
+    // Whether the photo's card is in the dataset at all.
+    pub known: bool,
+    // Whether the best candidate is the photo's card.
+    pub correct: bool,
+}
+
+impl Sample {
+    // Right means accepting a correct candidate or rejecting any other one.
+    fn right(&self, rejection: &Rejection) -> bool {
+        rejection.accepts(self.distance, self.gap) == self.correct
+    }
+}
+
+// Tries every threshold seen in the samples and picks the pair that gets
+// the most samples right, preferring looser thresholds on ties. Returns it
+// with the number of samples it gets right.
+pub fn calibrate(samples: &[Sample]) -> (Rejection, usize) {
+    let mut distances = samples.iter().map(|s| s.distance).collect::<Vec<_>>();
+    distances.sort_unstable_by(|a, b| b.cmp(a));
+    distances.dedup();
+
+    let mut gaps = samples.iter().filter_map(|s| s.gap).collect::<Vec<_>>();
+    gaps.sort_unstable();
+    gaps.dedup();
+
+    let max_distances = std::iter::once(None).chain(distances.into_iter().map(Some)).collect::<Vec<_>>();
+    let min_gaps = std::iter::once(None).chain(gaps.into_iter().map(Some)).collect::<Vec<_>>();
+
+    let mut best = (Rejection::default(), samples.iter().filter(|s| s.right(&Rejection::default())).count());
+    for &max_distance in max_distances.iter() {
+        for &min_gap in min_gaps.iter() {
+            let rejection = Rejection { max_distance, min_gap };
+            let right = samples.iter().filter(|s| s.right(&rejection)).count();
+
+            if right > best.1 {
+                best = (rejection, right);
+            }
+        }
+    }
+
+    best
+}
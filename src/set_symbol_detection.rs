@@ -1,76 +1,433 @@
 use image::GenericImageView;
+use rustfft::num_complex::Complex;
 
-#[inline]
-fn score(image: &image::DynamicImage, template: &image::DynamicImage, x: u32, y: u32, template_total_squared: f32) -> f32 {
-    let mut score = 0f32;
+pub struct Detection {
+    // Lowest score over every position, scale and angle searched, whether or
+    // not it's under the threshold. Lower is better.
+    pub score: f32,
+    // Top left corner of the transformed template at that position.
+    pub location: (u32, u32),
+    pub scale: f32,
+    // In degrees, clockwise.
+    pub angle: f32,
+    // The image with every position under the threshold outlined, for the
+    // best scale and angle, if asked.
+    pub marked: Option<image::ImageBuffer<image::Rgba<u8>, Vec<u8>>>,
+}
+
+// Template scales and angles (in degrees) to try. The template as it is gets
+// tried first, and if it isn't found, the steps x steps grid over both ranges
+// is searched. Either way the best point is then refined, halving the step
+// every time. Every scale and angle costs another full match, so by default
+// the range is small.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SearchRange {
+    pub scales: (f32, f32),
+    pub angles: (f32, f32),
+    pub steps: usize,
+    pub refinements: usize,
+}
+
+impl Default for SearchRange {
+    fn default() -> Self {
+        SearchRange::narrow()
+    }
+}
+
+impl SearchRange {
+    // Only the template as it is.
+    pub fn exact() -> Self {
+        SearchRange { scales: (1.0, 1.0), angles: (0.0, 0.0), steps: 1, refinements: 0 }
+    }
+
+    // Symbols up to 5% bigger or smaller than the template, or up to 2
+    // degrees off. 9 matches per template and area once the template as it
+    // is is found, up to 17 otherwise.
+    pub fn narrow() -> Self {
+        SearchRange {
+            scales: (0.95, 1.05),
+            angles: (-2.0, 2.0),
+            steps: 3,
+            refinements: 1,
+        }
+    }
+
+    // Symbols printed up to 15% bigger or smaller than the template, or warped
+    // up to 4 degrees off. Up to 25 matches per template and area.
+    pub fn wide() -> Self {
+        SearchRange {
+            scales: (0.85, 1.15),
+            angles: (-4.0, 4.0),
+            steps: 3,
+            refinements: 2,
+        }
+    }
+
+    // exact, narrow or wide.
+    pub fn named(name: &str) -> Option<Self> {
+        match name {
+            "exact" => Some(SearchRange::exact()),
+            "narrow" => Some(SearchRange::narrow()),
+            "wide" => Some(SearchRange::wide()),
+            _ => None,
+        }
+    }
+
+    // From { "scales": [0.95, 1.05], "angles": [-2, 2], "steps": 3, "refinements": 1 }.
+    pub fn from_json(json: &serde_json::Value) -> Result<Self, String> {
+        let pair = |name: &str| {
+            let numbers = json.get(name).and_then(|p| p.as_array()).and_then(|p| p.iter().map(|n| n.as_f64()).collect::<Option<Vec<_>>>());
+            match numbers.as_deref() {
+                Some(&[low, high]) if low <= high => Ok((low as f32, high as f32)),
+                _ => Err(format!("{} must be [low, high]", name)),
+            }
+        };
+        let count = |name: &str| json.get(name).and_then(|n| n.as_u64()).map(|n| n as usize).ok_or_else(|| format!("{} must be a number", name));
+
+        Ok(SearchRange {
+            scales: pair("scales")?,
+            angles: pair("angles")?,
+            steps: count("steps")?,
+            refinements: count("refinements")?,
+        })
+    }
 
-    let mut totes_image = 0f32;
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "scales": [self.scales.0, self.scales.1],
+            "angles": [self.angles.0, self.angles.1],
+            "steps": self.steps,
+            "refinements": self.refinements,
+        })
+    }
+}
+
+// A rectangle of the warped card where a set symbol can be. Templates are
+// searched at every position where they fit entirely inside it (and inside
+// the image).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SearchArea {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
 
-    for dy in 0 .. template.height() {
-        for dx in 0 .. template.width() {
-            let template_pixel = unsafe { template.unsafe_get_pixel(dx, dy) };
+// A transformed template's luma values and alpha mask.
+struct Prepared {
+    width: u32,
+    height: u32,
+    mask: Vec<f64>,
+    values: Vec<f64>,
+    total_squared: f64,
+}
 
-            if template_pixel[3] != 0 {
-                let image_value = unsafe { image.unsafe_get_pixel(x + dx, y + dy)[0] as f32 };
-                let template_value = template_pixel[0] as f32;
+impl Prepared {
+    fn new(template: &image::RgbaImage) -> Self {
+        let (width, height) = template.dimensions();
+        let mut prepared = Prepared {
+            width,
+            height,
+            mask: vec![0.0; (width * height) as usize],
+            values: vec![0.0; (width * height) as usize],
+            total_squared: 0.0,
+        };
 
-                totes_image += image_value.powf(2.0);
-                score += (image_value - template_value).powf(2.0);
+        for dy in 0 .. height {
+            for dx in 0 .. width {
+                let template_pixel = template.get_pixel(dx, dy);
+                if template_pixel[3] != 0 {
+                    let template_value = template_pixel[0] as f64;
+                    prepared.mask[(dy * width + dx) as usize] = 1.0;
+                    prepared.values[(dy * width + dx) as usize] = template_value;
+                    prepared.total_squared += template_value * template_value;
+                }
             }
         }
+
+        prepared
     }
+}
 
-    score / (totes_image * template_total_squared).sqrt()
+// The best match so far.
+struct Best {
+    score: f32,
+    location: (u32, u32),
+    scale: f32,
+    angle: f32,
+    // Of the transformed template.
+    size: (u32, u32),
+    // Every location under the threshold.
+    found: Vec<(u32, u32)>,
 }
 
-pub fn detect(image: &image::DynamicImage, template: &image::DynamicImage, threshold: f32) -> (f32, image::ImageBuffer<image::Rgba<u8>, Vec<u8>>) {
-    let mut marked = image.clone().to_rgba8();
+// Nearest neighbour, so that the alpha mask stays as sharp as the original's.
+fn transform(template: &image::DynamicImage, scale: f32, angle: f32) -> image::RgbaImage {
+    let rgba = template.to_rgba8();
+    if scale == 1.0 && angle == 0.0 {
+        return rgba;
+    }
+
+    let (width, height) = template.dimensions();
+    let scaled = image::imageops::resize(
+        &rgba,
+        ((width as f32 * scale).round() as u32).max(1),
+        ((height as f32 * scale).round() as u32).max(1),
+        image::imageops::FilterType::Nearest,
+    );
+
+    imageproc::geometric_transformations::rotate_about_center(
+        &scaled,
+        angle.to_radians(),
+        imageproc::geometric_transformations::Interpolation::Nearest,
+        image::Rgba([0, 0, 0, 0]),
+    )
+}
+
+fn transpose(from: &[Complex<f64>], to: &mut [Complex<f64>], width: usize, height: usize) {
+    for y in 0..height {
+        for x in 0..width {
+            to[x * height + y] = from[y * width + x];
+        }
+    }
+}
+
+// In place 2D FFT of a row major width x height grid, as row FFTs followed by
+// column FFTs. Unnormalized in both directions. The planner decides the
+// direction, and keeps the FFTs it plans for the next call.
+fn fft2d(planner: &mut rustfft::FFTplanner<f64>, data: &mut [Complex<f64>], width: usize, height: usize) {
+    let mut output = vec![Complex::new(0.0, 0.0); data.len()];
+
+    planner.plan_fft(width).process_multi(data, &mut output);
+    transpose(&output, data, width, height);
+    planner.plan_fft(height).process_multi(data, &mut output);
+    transpose(&output, data, height, width);
+}
+
+// Spectrum of a w x h signal, zero padded to width x height.
+fn spectrum<F>(planner: &mut rustfft::FFTplanner<f64>, w: usize, h: usize, width: usize, height: usize, value: F) -> Vec<Complex<f64>> where F: Fn(usize, usize) -> f64 {
+    let mut data = vec![Complex::new(0.0, 0.0); width * height];
+    for y in 0..h {
+        for x in 0..w {
+            data[y * width + x] = Complex::new(value(x, y), 0.0);
+        }
+    }
 
-    let mut template_total_squared = 0f32;
-    for dy in 0 .. template.height() {
-        for dx in 0 .. template.width() {
-            let template_pixel = unsafe { template.unsafe_get_pixel(dx, dy) };
-            if template_pixel[3] != 0 {
-                let template_value = template_pixel[0] as f32;
-                template_total_squared += template_value.powf(2.0);
+    fft2d(planner, &mut data, width, height);
+    data
+}
+
+// Cross-correlation from spectra: out[y * width + x] is the sum of
+// kernel[ky][kx] * signal[y + ky][x + kx]. Positions where the kernel wraps
+// around the grid are meaningless.
+fn correlate(
+    inverse: &mut rustfft::FFTplanner<f64>,
+    signal: &[Complex<f64>],
+    kernel: &[Complex<f64>],
+    width: usize,
+    height: usize,
+) -> Vec<f64> {
+    let mut product = signal.iter().zip(kernel.iter()).map(|(s, k)| s * k.conj()).collect::<Vec<_>>();
+    fft2d(inverse, &mut product, width, height);
+
+    let scale = (width * height) as f64;
+    product.into_iter().map(|c| c.re / scale).collect()
+}
+
+// Top left corners for a template of the given size in every area, as
+// (x range, y range).
+fn ranges(areas: &[SearchArea], width: u32, height: u32, tw: u32, th: u32) -> Vec<(std::ops::Range<u32>, std::ops::Range<u32>)> {
+    areas
+        .iter()
+        .map(|a| {
+            (
+                a.x .. (a.x + a.width).min(width).saturating_sub(tw),
+                a.y .. (a.y + a.height).min(height).saturating_sub(th),
+            )
+        })
+        .collect()
+}
+
+// Masked, normalized sum of squared differences between the template's luma
+// and the image under every searched position:
+//
+//   sum(M (I - T)^2) / sqrt(sum(M I^2) * sum(M T^2))
+//
+// where M is the template's alpha mask. Expanding the square leaves
+// sum(M I^2) and sum(M T I), two cross-correlations, which are computed with
+// FFTs over each search area instead of pixel by pixel. The image patches'
+// spectra only depend on the area and FFT size, so they're shared across
+// scales and angles.
+struct Matcher<'a> {
+    image: &'a image::GrayImage,
+    areas: &'a [SearchArea],
+    spectra: std::collections::HashMap<Patch, Spectra>,
+    forward: rustfft::FFTplanner<f64>,
+    inverse: rustfft::FFTplanner<f64>,
+}
+
+// The part of the image under every position in an area, for templates of a
+// given size, and the size of the FFTs over it.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct Patch {
+    area: usize,
+    width: usize,
+    height: usize,
+    fft_width: usize,
+    fft_height: usize,
+}
+
+// Spectra of a patch and of its square.
+struct Spectra {
+    values: Vec<Complex<f64>>,
+    squared: Vec<Complex<f64>>,
+}
+
+impl<'a> Matcher<'a> {
+    // The best (score, location) and every location under the threshold.
+    fn search(&mut self, template: &Prepared, threshold: f32) -> (f32, (u32, u32), Vec<(u32, u32)>) {
+        let (width, height) = self.image.dimensions();
+        let (tw, th) = (template.width, template.height);
+
+        let mut best_score = f32::INFINITY;
+        let mut best_location = (0, 0);
+        let mut found = vec![];
+
+        for (area, (xs, ys)) in ranges(self.areas, width, height, tw, th).iter().enumerate() {
+            if xs.is_empty() || ys.is_empty() {
+                continue;
+            }
+
+            let (columns, rows) = ((xs.end - xs.start) as usize, (ys.end - ys.start) as usize);
+            let (pw, ph) = (columns + tw as usize - 1, rows + th as usize - 1);
+            let (fft_width, fft_height) = (pw.next_power_of_two(), ph.next_power_of_two());
+
+            let image = self.image;
+            let forward = &mut self.forward;
+            let patch = Patch { area, width: pw, height: ph, fft_width, fft_height };
+            let spectra = self.spectra.entry(patch).or_insert_with(|| {
+                let pixel = |x: usize, y: usize| image.get_pixel(xs.start + x as u32, ys.start + y as u32)[0] as f64;
+
+                Spectra {
+                    values: spectrum(forward, pw, ph, fft_width, fft_height, pixel),
+                    squared: spectrum(forward, pw, ph, fft_width, fft_height, |x, y| pixel(x, y).powi(2)),
+                }
+            });
+
+            let kernel_mask = spectrum(forward, tw as usize, th as usize, fft_width, fft_height, |x, y| template.mask[y * tw as usize + x]);
+            let kernel_values = spectrum(forward, tw as usize, th as usize, fft_width, fft_height, |x, y| template.values[y * tw as usize + x]);
+
+            let energy = correlate(&mut self.inverse, &spectra.squared, &kernel_mask, fft_width, fft_height);
+            let cross = correlate(&mut self.inverse, &spectra.values, &kernel_values, fft_width, fft_height);
+
+            for y in 0 .. rows {
+                for x in 0 .. columns {
+                    let i = y * fft_width + x;
+                    let normalization = (energy[i] * template.total_squared).sqrt();
+                    let p = if normalization > 0.0 {
+                        ((energy[i] - 2.0 * cross[i] + template.total_squared) / normalization) as f32
+                    } else {
+                        f32::INFINITY
+                    };
+
+                    let position = (xs.start + x as u32, ys.start + y as u32);
+                    if p < best_score {
+                        best_score = p;
+                        best_location = position;
+                    }
+
+                    if p < threshold {
+                        found.push(position);
+                    }
+                }
             }
         }
+
+        (best_score, best_location, found)
+    }
+}
+
+fn steps(range: (f32, f32), steps: usize) -> (Vec<f32>, f32) {
+    if steps <= 1 || range.0 == range.1 {
+        return (vec![(range.0 + range.1) / 2.0], 0.0);
     }
-    let mut positions = vec![];
 
-    for y in 900..image.height().min(1000) - template.height() {
-        for x in 10..image.width().min(130) - template.width() {
-            positions.push((x, y));
+    let step = (range.1 - range.0) / (steps - 1) as f32;
+    ((0..steps).map(|i| range.0 + step * i as f32).collect(), step)
+}
+
+pub fn detect(image: &image::GrayImage, template: &image::DynamicImage, threshold: f32, areas: &[SearchArea], range: &SearchRange, mark: bool) -> Detection {
+    let mut matcher = Matcher {
+        image,
+        areas,
+        spectra: std::collections::HashMap::new(),
+        forward: rustfft::FFTplanner::new(false),
+        inverse: rustfft::FFTplanner::new(true),
+    };
+
+    let mut best = Best { score: f32::INFINITY, location: (0, 0), scale: 1.0, angle: 0.0, size: (0, 0), found: vec![] };
+    let mut tried = std::collections::HashSet::new();
+    let mut try_transform = |scale: f32, angle: f32, best: &mut Best| {
+        if !tried.insert((scale.to_bits(), angle.to_bits())) {
+            return;
         }
+
+        let prepared = Prepared::new(&transform(template, scale, angle));
+        let (score, location, found) = matcher.search(&prepared, threshold);
+
+        if score < best.score || best.size == (0, 0) {
+            *best = Best { score, location, scale, angle, size: (prepared.width, prepared.height), found };
+        }
+    };
+
+    let contains = |range: (f32, f32), value: f32| range.0.min(range.1) <= value && value <= range.0.max(range.1);
+    if contains(range.scales, 1.0) && contains(range.angles, 0.0) {
+        try_transform(1.0, 0.0, &mut best);
     }
 
-    for y in 850..image.height() - template.height() {
-        for x in 600..image.width() - template.width() {
-            positions.push((x, y));
+    // Only worth searching the whole grid if the template as it is wasn't
+    // found, but the refinements still look for a better scale and angle.
+    let (scales, mut scale_step) = steps(range.scales, range.steps);
+    let (angles, mut angle_step) = steps(range.angles, range.steps);
+    if best.score >= threshold {
+        for &scale in scales.iter() {
+            for &angle in angles.iter() {
+                try_transform(scale, angle, &mut best);
+            }
         }
     }
 
-    for y in 500..image.height().min(620) - template.height() {
-        for x in 600..image.width() - template.width() {
-            positions.push((x, y));
+    for _ in 0..range.refinements {
+        scale_step /= 2.0;
+        angle_step /= 2.0;
+
+        let (scale, angle) = (best.scale, best.angle);
+        for &ds in [-scale_step, 0.0, scale_step].iter() {
+            for &da in [-angle_step, 0.0, angle_step].iter() {
+                try_transform(scale + ds, angle + da, &mut best);
+            }
         }
     }
 
-    let mut best_score = 1.0f32;
-    for &(x, y) in positions.iter() {
-        let p = score(image, template, x, y, template_total_squared);
-        if p < threshold {
-            best_score = best_score.min(p);
-            for k in 0..template.width(){
+    let Best { score, location, scale, angle, size: (tw, th), found } = best;
+
+    let marked = if mark {
+        let mut marked = image::DynamicImage::ImageLuma8(image.clone()).to_rgba8();
+        for &(x, y) in found.iter() {
+            for k in 0..tw {
                 marked.put_pixel(x+k, y, image::Rgba([255, 0, 255, 255]));
-                marked.put_pixel(x+k, y+template.height()-1, image::Rgba([255, 0, 255, 255]));
+                marked.put_pixel(x+k, y+th-1, image::Rgba([255, 0, 255, 255]));
             }
-            for k in 0..template.height() {
+            for k in 0..th {
                 marked.put_pixel(x, y+k, image::Rgba([255, 0, 255, 255]));
-                marked.put_pixel(x+template.width()-1, y+k, image::Rgba([255, 0, 255, 255]));
+                marked.put_pixel(x+tw-1, y+k, image::Rgba([255, 0, 255, 255]));
             }
         }
-    }
 
-    (best_score, marked)
+        Some(marked)
+    } else {
+        None
+    };
+
+    Detection { score, location, scale, angle, marked }
 }
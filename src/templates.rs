@@ -0,0 +1,487 @@
+use crate::layout;
+use crate::metadata;
+use crate::set_symbol_detection;
+
+// A set symbol template, as listed in the manifest.
+pub struct Template {
+    // Set code, matched against CardMetadata::set.
+    pub code: String,
+    // A raster image, or an SVG rasterized at size pixels high.
+    pub path: std::path::PathBuf,
+    pub image: image::DynamicImage,
+    // Height of the symbol on a warped card, for SVG images. None means
+    // symbol_size of its eras.
+    pub size: Option<u32>,
+    // Highest set_symbol_detection score that still counts as found.
+    pub threshold: f32,
+    // Scales and angles the threshold was calibrated for, which detect_sets
+    // then searches instead of ProcessingOptions::set_search.
+    pub search: Option<set_symbol_detection::SearchRange>,
+    // Empty means the set symbol boxes of the layouts it's searched with.
+    pub areas: Vec<set_symbol_detection::SearchArea>,
+    // Series (CardMetadata::series) whose cards can carry this symbol. Empty
+    // means any.
+    pub eras: Vec<String>,
+}
+
+impl Template {
+    pub fn applies_to(&self, metadata: &metadata::CardMetadata) -> bool {
+        match &metadata.series {
+            _ if self.eras.is_empty() => true,
+            Some(series) => self.eras.iter().any(|era| era.eq_ignore_ascii_case(series)),
+            None => true,
+        }
+    }
+
+    // Where to look for it on a card warped to width x height, given the
+    // layouts of the cards it could be on.
+    pub fn search_areas(&self, layouts: &[&layout::Layout], width: u32, height: u32) -> Vec<set_symbol_detection::SearchArea> {
+        if !self.areas.is_empty() {
+            return self.areas.clone();
+        }
+
+        let eras = self.eras.iter().filter_map(|e| layout::Era::of_series(e)).collect::<Vec<_>>();
+        let own = layouts.iter().filter(|l| eras.contains(&l.era)).copied().collect::<Vec<_>>();
+
+        if own.is_empty() {
+            layout::set_symbol_areas(layouts.iter().copied(), width, height)
+        } else {
+            layout::set_symbol_areas(own, width, height)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TemplateError {
+    Io(std::io::Error),
+    Manifest(String),
+    // (template code, image path, error) for every image that failed to load.
+    Images(Vec<(String, std::path::PathBuf, String)>),
+}
+
+impl std::fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TemplateError::Io(e) => write!(f, "{}", e),
+            TemplateError::Manifest(e) => write!(f, "invalid manifest: {}", e),
+            TemplateError::Images(failed) => {
+                for (i, (code, path, e)) in failed.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "template {}: {:?}: {}", code, path, e)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+impl From<std::io::Error> for TemplateError {
+    fn from(e: std::io::Error) -> Self {
+        TemplateError::Io(e)
+    }
+}
+
+fn invalid(message: String) -> TemplateError {
+    TemplateError::Manifest(message)
+}
+
+// For templates without a manifest entry.
+pub const DEFAULT_THRESHOLD: f32 = 0.15;
+
+fn is_svg(path: &std::path::Path) -> bool {
+    path.extension().map(|e| e.eq_ignore_ascii_case("svg")).unwrap_or(false)
+}
+
+// Renders an SVG to the given height, with its luma in every colour channel
+// (matching only reads the first one) and an alpha mask that's either fully
+// opaque or fully transparent, like a hand made template's.
+pub fn rasterize(path: &std::path::Path, height: u32) -> Result<image::DynamicImage, String> {
+    let tree = usvg::Tree::from_file(path, &usvg::Options::default()).map_err(|e| e.to_string())?;
+    let rendered = resvg::render(&tree, usvg::FitTo::Height(height), None).ok_or("nothing to render")?;
+
+    let (width, height) = (rendered.width(), rendered.height());
+    let mut image = image::RgbaImage::from_raw(width, height, rendered.take()).ok_or("bad render size")?;
+
+    for pixel in image.pixels_mut() {
+        let [r, g, b, a] = pixel.0;
+        let luma = (0.299 * r as f32 + 0.587 * g as f32 + 0.114 * b as f32).round() as u8;
+        *pixel = image::Rgba([luma, luma, luma, if a >= 128 { 255 } else { 0 }]);
+    }
+
+    Ok(image::DynamicImage::ImageRgba8(image))
+}
+
+// The eras of the cards of every set (by lowercase set code), for templates
+// that don't list their own.
+pub type SetEras = std::collections::HashMap<String, Vec<layout::Era>>;
+
+pub fn set_eras<'a, I>(cards: I) -> SetEras where I: IntoIterator<Item = &'a metadata::CardMetadata> {
+    let mut eras = SetEras::new();
+    for card in cards {
+        if let (Some(set), Some(era)) = (&card.set, layout::Era::of(card)) {
+            let set = eras.entry(set.to_ascii_lowercase()).or_default();
+            if !set.contains(&era) {
+                set.push(era);
+            }
+        }
+    }
+
+    eras
+}
+
+// Height to rasterize SVGs at on a 734x1024 warped card, the mean printed
+// symbol height of the given eras' layouts, or of every layout without any.
+pub fn symbol_size(eras: &[layout::Era]) -> u32 {
+    let eras = if eras.is_empty() { &layout::ERAS[..] } else { eras };
+    let height = eras.iter().map(|&era| layout::Layout::of(era).symbol_height).sum::<f64>() / eras.len() as f64;
+
+    ((height * 1024.0).round() as u32).max(1)
+}
+
+// The eras a template's symbol is printed in: the ones it lists, or else
+// those of its set's cards.
+fn template_eras(code: &str, eras: &[String], set_eras: &SetEras) -> Vec<layout::Era> {
+    let own = eras.iter().filter_map(|e| layout::Era::of_series(e)).collect::<Vec<_>>();
+    if !own.is_empty() {
+        return own;
+    }
+
+    set_eras.get(&code.to_ascii_lowercase()).cloned().unwrap_or_default()
+}
+
+fn open(path: &std::path::Path, size: Option<u32>, eras: &[layout::Era]) -> Result<image::DynamicImage, String> {
+    if is_svg(path) {
+        rasterize(path, size.unwrap_or_else(|| symbol_size(eras)))
+    } else {
+        image::open(path).map_err(|e| e.to_string())
+    }
+}
+
+// One entry of the manifest, before loading its image.
+struct Entry {
+    code: String,
+    image: std::path::PathBuf,
+    size: Option<u32>,
+    threshold: f32,
+    search: Option<set_symbol_detection::SearchRange>,
+    areas: Vec<set_symbol_detection::SearchArea>,
+    eras: Vec<String>,
+}
+
+fn parse(index: usize, value: &serde_json::Value) -> Result<Entry, TemplateError> {
+    let field = |name: &str| value.get(name).ok_or_else(|| invalid(format!("template {} has no {}", index, name)));
+
+    let code = field("code")?.as_str().ok_or_else(|| invalid(format!("template {}: code must be a string", index)))?;
+    let image = match value.get("image") {
+        None => format!("{}.svg", code),
+        Some(image) => image
+            .as_str()
+            .ok_or_else(|| invalid(format!("template {}: image must be a string", index)))?
+            .to_string(),
+    };
+    let threshold = field("threshold")?
+        .as_f64()
+        .filter(|&t| t > 0.0)
+        .ok_or_else(|| invalid(format!("template {}: threshold must be a positive number", index)))?;
+
+    let size = match value.get("size") {
+        None => None,
+        Some(size) => Some(
+            size.as_u64()
+                .filter(|&s| s > 0)
+                .ok_or_else(|| invalid(format!("template {}: size must be a positive number", index)))? as u32,
+        ),
+    };
+
+    let search = match value.get("search") {
+        None => None,
+        Some(search) => Some(
+            set_symbol_detection::SearchRange::from_json(search)
+                .map_err(|e| invalid(format!("template {}: search: {}", index, e)))?,
+        ),
+    };
+
+    let areas = match value.get("areas") {
+        None => vec![],
+        Some(areas) => areas
+            .as_array()
+            .ok_or_else(|| invalid(format!("template {}: areas must be an array", index)))?
+            .iter()
+            .map(|area| {
+                let numbers = area.as_array().and_then(|a| a.iter().map(|n| n.as_u64()).collect::<Option<Vec<_>>>());
+                match numbers.as_deref() {
+                    Some(&[x, y, width, height]) => Ok(set_symbol_detection::SearchArea {
+                        x: x as u32,
+                        y: y as u32,
+                        width: width as u32,
+                        height: height as u32,
+                    }),
+                    _ => Err(invalid(format!("template {}: areas must be [x, y, width, height]", index))),
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+    };
+
+    let eras = match value.get("eras") {
+        None => vec![],
+        Some(eras) => eras
+            .as_array()
+            .and_then(|eras| eras.iter().map(|e| e.as_str().map(|e| e.to_string())).collect::<Option<Vec<_>>>())
+            .ok_or_else(|| invalid(format!("template {}: eras must be an array of strings", index)))?,
+    };
+
+    Ok(Entry {
+        code: code.to_string(),
+        image: std::path::PathBuf::from(image),
+        size,
+        threshold: threshold as f32,
+        search,
+        areas,
+        eras,
+    })
+}
+
+// Reads a JSON manifest like
+//
+//   { "templates": [
+//     { "code": "ssh", "image": "ssh.svg", "size": 36, "threshold": 0.10,
+//       "search": { "scales": [0.95, 1.05], "angles": [-2, 2], "steps": 3, "refinements": 1 },
+//       "areas": [[10, 900, 120, 100]], "eras": ["Sword & Shield"] }
+//   ] }
+//
+// where image paths are relative to the manifest, {code}.svg by default, and
+// size (the height SVGs are rasterized at, symbol_size of the eras, or of
+// the eras of its set's cards in set_eras, by default), search (the range the threshold was calibrated for), areas (rectangles of the warped card, as x, y, width and height,
+// by default the layout's set symbol boxes) and eras are optional. Every image
+// that fails to load is reported.
+pub fn load(manifest: &std::path::Path, set_eras: &SetEras) -> Result<Vec<Template>, TemplateError> {
+    let contents = std::fs::read_to_string(manifest)?;
+    let json: serde_json::Value = serde_json::from_str(&contents).map_err(|e| invalid(e.to_string()))?;
+    let directory = manifest.parent().unwrap_or_else(|| std::path::Path::new(""));
+
+    let entries = json
+        .get("templates")
+        .and_then(|t| t.as_array())
+        .ok_or_else(|| invalid("expected a templates array".to_string()))?;
+
+    let mut templates = vec![];
+    let mut failed = vec![];
+
+    for (i, entry) in entries.iter().enumerate() {
+        let Entry { code, image, size, threshold, search, areas, eras } = parse(i, entry)?;
+        let path = directory.join(image);
+
+        match open(&path, size, &template_eras(&code, &eras, set_eras)) {
+            Ok(image) => templates.push(Template { code, path, image, size, threshold, search, areas, eras }),
+            Err(e) => failed.push((code, path, e)),
+        }
+    }
+
+    if failed.is_empty() {
+        Ok(templates)
+    } else {
+        Err(TemplateError::Images(failed))
+    }
+}
+
+// Every {code}.svg in the directory, at the symbol size of its set's eras and
+// the default threshold, for when there's no manifest.
+pub fn load_svgs(directory: &std::path::Path, set_eras: &SetEras) -> Result<Vec<Template>, TemplateError> {
+    let mut paths = std::fs::read_dir(directory)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    paths.retain(|path| is_svg(path));
+    paths.sort();
+
+    let mut templates = vec![];
+    let mut failed = vec![];
+
+    for path in paths {
+        let code = path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+
+        match open(&path, None, &template_eras(&code, &[], set_eras)) {
+            Ok(image) => templates.push(Template {
+                code,
+                path,
+                image,
+                size: None,
+                threshold: DEFAULT_THRESHOLD,
+                search: None,
+                areas: vec![],
+                eras: vec![],
+            }),
+            Err(e) => failed.push((code, path, e)),
+        }
+    }
+
+    if failed.is_empty() {
+        Ok(templates)
+    } else {
+        Err(TemplateError::Images(failed))
+    }
+}
+
+// Writes a manifest that load reads back, with image paths relative to it.
+pub fn save(manifest: &std::path::Path, templates: &[Template]) -> std::io::Result<()> {
+    let directory = manifest.parent().unwrap_or_else(|| std::path::Path::new(""));
+
+    let entries = templates
+        .iter()
+        .map(|t| {
+            let image = t.path.strip_prefix(directory).unwrap_or(&t.path);
+
+            let mut entry = serde_json::json!({
+                "code": t.code,
+                "image": image.to_string_lossy(),
+                "threshold": t.threshold,
+            });
+            if let Some(size) = t.size {
+                entry["size"] = serde_json::json!(size);
+            }
+            if let Some(search) = &t.search {
+                entry["search"] = search.to_json();
+            }
+            if !t.areas.is_empty() {
+                entry["areas"] = t.areas.iter().map(|a| serde_json::json!([a.x, a.y, a.width, a.height])).collect();
+            }
+            if !t.eras.is_empty() {
+                entry["eras"] = serde_json::json!(t.eras);
+            }
+
+            entry
+        })
+        .collect::<Vec<_>>();
+
+    let json = serde_json::to_string_pretty(&serde_json::json!({ "templates": entries }))?;
+    std::fs::write(manifest, json + "\n")
+}
+
+// Pixels whose luma varies less than this across the aligned crops are part
+// of what every card of the set shares.
+const MAX_DEVIATION: f32 = 16.0;
+// Shared pixels this close to the background are the card frame, not the
+// symbol.
+const MIN_CONTRAST: f32 = 24.0;
+
+// Standard deviation and mean of every pixel of the crops, each shifted by its
+// offset, over the window where they all overlap.
+fn statistics(crops: &[image::GrayImage], offsets: &[(u32, u32)], width: u32, height: u32) -> (Vec<f32>, Vec<f32>) {
+    let mut sums = vec![0.0; (width * height) as usize];
+    let mut squares = vec![0.0; (width * height) as usize];
+
+    for (crop, &(dx, dy)) in crops.iter().zip(offsets.iter()) {
+        for y in 0..height {
+            for x in 0..width {
+                let value = crop.get_pixel(x + dx, y + dy)[0] as f32;
+                sums[(y * width + x) as usize] += value;
+                squares[(y * width + x) as usize] += value * value;
+            }
+        }
+    }
+
+    let n = crops.len() as f32;
+    let means = sums.iter().map(|s| s / n).collect::<Vec<_>>();
+    let deviations = squares
+        .iter()
+        .zip(means.iter())
+        .map(|(s, m)| (s / n - m * m).max(0.0).sqrt())
+        .collect();
+
+    (deviations, means)
+}
+
+// Offset of the crop within shift pixels of the reference's, as the one with
+// the smallest sum of squared differences over the overlapping window.
+fn align(reference: &image::GrayImage, crop: &image::GrayImage, shift: u32) -> (u32, u32) {
+    let (width, height) = reference.dimensions();
+    let (w, h) = (width - 2 * shift, height - 2 * shift);
+
+    let mut best = (f32::INFINITY, (shift, shift));
+    for dy in 0..=2 * shift {
+        for dx in 0..=2 * shift {
+            let mut ssd = 0.0;
+            for y in 0..h {
+                for x in 0..w {
+                    let d = reference.get_pixel(x + shift, y + shift)[0] as f32 - crop.get_pixel(x + dx, y + dy)[0] as f32;
+                    ssd += d * d;
+                }
+            }
+
+            if ssd < best.0 {
+                best = (ssd, (dx, dy));
+            }
+        }
+    }
+
+    best.1
+}
+
+// Builds a template out of same sized crops of the set symbol box of cards of
+// a single set: aligns them on the first one, within shift pixels, averages
+// them, and keeps the pixels that every card shares and that stand out from
+// the background (the median of the window's edge), grown by a pixel, as the
+// alpha mask. Trimmed to the mask. None if the crops don't share anything.
+pub fn extract(crops: &[image::GrayImage], shift: u32) -> Option<image::RgbaImage> {
+    let reference = crops.first()?;
+    let (width, height) = reference.dimensions();
+    if width <= 2 * shift || height <= 2 * shift || crops.iter().any(|c| c.dimensions() != (width, height)) {
+        return None;
+    }
+
+    let offsets = crops.iter().map(|c| align(reference, c, shift)).collect::<Vec<_>>();
+    let (w, h) = (width - 2 * shift, height - 2 * shift);
+    let (deviations, means) = statistics(crops, &offsets, w, h);
+
+    let mut edge = (0..w)
+        .flat_map(|x| vec![(x, 0), (x, h - 1)])
+        .chain((0..h).flat_map(|y| vec![(0, y), (w - 1, y)]))
+        .map(|(x, y)| means[(y * w + x) as usize])
+        .collect::<Vec<_>>();
+    edge.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let background = edge[edge.len() / 2];
+
+    let symbol = (0..(w * h) as usize)
+        .map(|i| deviations[i] < MAX_DEVIATION && (means[i] - background).abs() > MIN_CONTRAST)
+        .collect::<Vec<_>>();
+
+    let at = |x: i64, y: i64| x >= 0 && y >= 0 && x < w as i64 && y < h as i64 && symbol[(y * w as i64 + x) as usize];
+    let mask = (0..(w * h) as i64)
+        .map(|i| {
+            let (x, y) = (i % w as i64, i / w as i64);
+            (-1..=1).any(|dy| (-1..=1).any(|dx| at(x + dx, y + dy)))
+        })
+        .collect::<Vec<_>>();
+
+    let masked = (0..w * h).filter(|&i| mask[i as usize]).map(|i| (i % w, i / w));
+    let (x0, y0, x1, y1) = masked.fold(None, |bounds: Option<(u32, u32, u32, u32)>, (x, y)| match bounds {
+        Some((x0, y0, x1, y1)) => Some((x0.min(x), y0.min(y), x1.max(x), y1.max(y))),
+        None => Some((x, y, x, y)),
+    })?;
+
+    Some(image::ImageBuffer::from_fn(x1 - x0 + 1, y1 - y0 + 1, |x, y| {
+        let i = ((y + y0) * w + x + x0) as usize;
+        let value = means[i].round() as u8;
+        image::Rgba([value, value, value, if mask[i] { 255 } else { 0 }])
+    }))
+}
+
+// The loosest threshold under which at most the given fraction of negative
+// scores (the template's best score on cards of other sets) fall, with the
+// fraction of positive scores (on cards of its own set) under it. None
+// without negatives, or when that threshold would be zero, since nothing
+// scores under it and detect_sets divides by it.
+pub fn calibrate(positives: &[f32], negatives: &[f32], false_positive_rate: f32) -> Option<(f32, f32)> {
+    let mut negatives = negatives.to_vec();
+    negatives.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+
+    let allowed = (false_positive_rate * negatives.len() as f32).floor() as usize;
+    let threshold = *negatives.get(allowed.min(negatives.len().checked_sub(1)?))?;
+    if threshold <= 0.0 {
+        return None;
+    }
+
+    let found = positives.iter().filter(|&&s| s < threshold).count();
+    Some((threshold, found as f32 / positives.len().max(1) as f32))
+}
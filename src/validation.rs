@@ -0,0 +1,46 @@
+// Labeled photos used to calibrate thresholds, listed in a text file with one
+// "<image> <card>" per line. Image paths are relative to the file, and cards
+// are dataset image file stems, or "unknown" for cards that aren't in the
+// dataset. Empty lines and lines starting with # are ignored.
+pub struct Label {
+    pub image: std::path::PathBuf,
+    pub card: Option<String>,
+}
+
+pub fn read_labels(filename: &str) -> std::io::Result<Vec<Label>> {
+    let directory = std::path::Path::new(filename).parent().unwrap_or_else(|| std::path::Path::new(""));
+    let mut labels = vec![];
+
+    for (i, line) in std::fs::read_to_string(filename)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut fields = line.split_whitespace();
+        match (fields.next(), fields.next(), fields.next()) {
+            (Some(image), Some(card), None) => labels.push(Label {
+                image: directory.join(image),
+                card: if card == "unknown" { None } else { Some(card.to_string()) },
+            }),
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("{}:{}: expected \"<image> <card>\"", filename, i + 1),
+                ))
+            },
+        }
+    }
+
+    Ok(labels)
+}
+
+impl Label {
+    // Whether a dataset entry is the labeled card.
+    pub fn is(&self, entry: &crate::DatasetEntry) -> bool {
+        match &self.card {
+            Some(card) => entry.path.file_stem().and_then(|s| s.to_str()) == Some(card.as_str()),
+            None => false,
+        }
+    }
+}